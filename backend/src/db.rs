@@ -1,11 +1,31 @@
 use crate::image::{ImageView, BoxedStorableImage, SyncResponse};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::broadcast;
 use tokio::sync::oneshot;
-use crate::model::model::{DataValue, Model, Record, DataError, Vector2D};
-use crate::model::async_model_reader::{do_load_async, load_model_into};
+use crate::model::model::{DataValue, Model, Record, Field, DataError, Vector2D, FieldType, IncompatibleError};
+use crate::model::async_model_reader::{default_type, do_load_async, load_model_into, reload_tiles_into};
 use crate::model::datatypes::DataTypes;
+use crate::model::datatypes::image::IMAGE_TYPE;
+use crate::io::bitmap_font::DEFAULT_FONT;
+use crate::model::filter::Filter;
+use crate::model::legend::{Legend, FieldRef};
+use crate::model::ordering::compare_nulls_last;
+use crate::model::colors::RGB;
+use crate::model::layout::{layout_record, draw_record, erase_record, record_size, GLYPH_SIZE};
+use crate::model::colors::{BLANK, META};
+use crate::model::allocator::{FreeSpace, MARGIN};
+use crate::model::references::{line_start, trace_own_reference_line, route_reference_line};
+use crate::io::image_io::load_image;
+use crate::io::snapshots::{Snapshot, create_snapshot, list_snapshots, restore_snapshot, prune_snapshots};
+use crate::io::history::{History, FieldChange};
 use std::fmt::{Debug, Formatter};
+use std::cmp::Ordering;
+use std::io::ErrorKind;
+use std::path::Path;
 use std::sync::{Mutex, Arc};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use log::*;
 
 
@@ -13,13 +33,94 @@ use log::*;
 pub struct DataRecord {
     pub id: Vector2D,
     pub column: String,
+    // from the legend
+    pub column_name: Option<String>,
     pub fields: Vec<DataFieldValue>,
+    // hash of the record pixels, see record_version
+    pub version: u64,
 }
 
 #[derive(Debug)]
 pub struct DataFieldValue {
     pub value: DataValue,
     pub reference: Option<Vector2D>,
+    // from the legend
+    pub name: Option<String>,
+    // whole referenced record, see DBQuery::expand
+    pub record: Option<Box<DataRecord>>,
+}
+
+
+#[derive(Debug)]
+pub struct RecordsPage {
+    pub records: Vec<DataRecord>,
+    // number of records matching the query, regardless of offset and limit
+    pub total: usize,
+    // index of the first returned record among matching ones
+    pub offset: usize,
+}
+
+
+#[derive(Debug)]
+pub struct Referrer {
+    pub record: DataRecord,
+    // indexes of the record fields referencing the record in question
+    pub fields: Vec<usize>,
+}
+
+
+#[derive(Debug)]
+pub struct DeletedRecord {
+    pub id: Vector2D,
+    // (record id, field index) of references which pointed to the deleted record
+    pub dangling_references: Vec<(Vector2D, usize)>,
+}
+
+// Changes of the database, see `DBHandle::subscribe`
+#[derive(Debug, Clone)]
+pub enum DBEvent {
+    RecordChanged { id: Vector2D },
+    RecordCreated { id: Vector2D },
+    RecordDeleted { id: Vector2D },
+    // the image was changed outside and records were read again, any of them may differ
+    ModelReloaded,
+}
+
+// events not received by a slow subscriber yet, older ones are dropped for it
+const EVENTS_CAPACITY: usize = 256;
+
+
+// Value which cannot be written into the field, descriptions are for humans
+#[derive(Debug)]
+pub struct InvalidValue {
+    pub field: FieldRef,
+    pub expected: String,
+    pub given: String,
+}
+
+impl InvalidValue {
+    fn new(field: u32, ftype: FieldType, error: IncompatibleError) -> Self {
+        let expected = ftype.name().unwrap_or("unknown").to_string();
+        match error {
+            IncompatibleError::TooLong { length, capacity } => InvalidValue {
+                field: FieldRef::Index(field),
+                expected: format!("{} of length up to {}", expected, capacity),
+                given: format!("{} of length {}", expected, length),
+            },
+            IncompatibleError::CannotParseValue(given) => InvalidValue { field: FieldRef::Index(field), expected, given },
+            IncompatibleError::InvalidSize => InvalidValue { field: FieldRef::Index(field), expected, given: "value of invalid size".to_string() },
+            IncompatibleError::InvalidDataType => InvalidValue { field: FieldRef::Index(field), expected, given: "value of other type".to_string() },
+        }
+    }
+
+    // field the record doesn't have
+    fn unknown_field(field: FieldRef, rec: &Record) -> Self {
+        InvalidValue {
+            field,
+            expected: format!("field index below {} or field name from the legend", rec.fields.len()),
+            given: "unknown field".to_string(),
+        }
+    }
 }
 
 
@@ -28,12 +129,17 @@ pub struct DBQuery {
     offset: Option<u32>,
     limit: Option<u32>,
     column: Option<String>,
-    ids: Option<Vec<Vector2D>>
+    ids: Option<Vec<Vector2D>>,
+    filters: Vec<Filter>,
+    order_by: Option<FieldRef>,
+    desc: bool,
+    after: Option<Vector2D>,
+    expand: u32,
 }
 
 impl DBQuery {
     pub fn new() -> Self {
-        Self { offset: None, limit: None, column: None, ids: None }
+        Self { offset: None, limit: None, column: None, ids: None, filters: vec![], order_by: None, desc: false, after: None, expand: 0 }
     }
 
     pub fn offset(&mut self, offset: u32) -> &mut Self {
@@ -46,6 +152,7 @@ impl DBQuery {
         self
     }
 
+    // column color or name from the legend
     pub fn column(&mut self, column: String) -> &mut Self {
         self.column = Some(column);
         self
@@ -56,28 +163,80 @@ impl DBQuery {
         self
     }
 
+    // all filters shall match
+    pub fn filter(&mut self, filter: Filter) -> &mut Self {
+        self.filters.push(filter);
+        self
+    }
+
+    // cursor: page starts right after the record with given id, offset is ignored
+    pub fn after(&mut self, id: Vector2D) -> &mut Self {
+        self.after = Some(id);
+        self
+    }
+
+    // records are sorted by value of the field before offset and limit are applied, nulls are the last
+    pub fn order_by(&mut self, field: FieldRef, desc: bool) -> &mut Self {
+        self.order_by = Some(field);
+        self.desc = desc;
+        self
+    }
+
+
+    // referenced records are included into reference fields up to `depth` levels deep,
+    // a record is not expanded inside itself, so cycles stop at the first repeated record
+    pub fn expand(&mut self, depth: u32) -> &mut Self {
+        self.expand = depth;
+        self
+    }
 
     pub fn build(&self) -> Self {
         Self {
             offset: self.offset,
             limit: self.limit,
             column: self.column.clone(),
-            ids: self.ids.clone()
+            ids: self.ids.clone(),
+            filters: self.filters.clone(),
+            order_by: self.order_by.clone(),
+            desc: self.desc,
+            after: self.after,
+            expand: self.expand,
         }
     }
 }
 
 pub enum DBMessage {
     GetModel { tx: oneshot::Sender<DBResult<Model>> },
-    GetRecords { query: DBQuery, tx: oneshot::Sender<DBResult<Vec<DataRecord>>> },
-    SetField { x: u32, y: u32, fi: u32, value: DataValue, tx: oneshot::Sender<DBResult<()>> },
-    CloneRecord { x: u32, y: u32, tx: oneshot::Sender<DBResult<DataRecord>> },
+    GetRecords { query: DBQuery, tx: oneshot::Sender<DBResult<RecordsPage>> },
+    GetLegend { tx: oneshot::Sender<DBResult<Legend>> },
+    GetReferrers { x: u32, y: u32, tx: oneshot::Sender<DBResult<Vec<Referrer>>> },
+    // client is stored in the history along with the change, the record should have one of expected_versions if any,
+    // answered with the new version
+    SetField { x: u32, y: u32, fi: u32, value: DataValue, client: String, expected_versions: Option<Vec<u64>>, tx: oneshot::Sender<DBResult<u64>> },
+    // all or nothing
+    SetFields { x: u32, y: u32, values: Vec<(FieldRef, DataValue)>, client: String, tx: oneshot::Sender<DBResult<()>> },
+    // oldest first
+    GetHistory { x: u32, y: u32, tx: oneshot::Sender<DBResult<Vec<FieldChange>>> },
+    // puts back pixels the field had before the change of this version
+    RevertField { x: u32, y: u32, version: u64, client: String, tx: oneshot::Sender<DBResult<()>> },
+    // redraws the line of the reference field, no target removes the line
+    SetReference { x: u32, y: u32, fi: u32, target: Option<Vector2D>, tx: oneshot::Sender<DBResult<()>> },
+    // the record should have one of expected_versions if any
+    CloneRecord { x: u32, y: u32, expected_versions: Option<Vec<u64>>, tx: oneshot::Sender<DBResult<DataRecord>> },
+    CreateRecord { column: RGB, field_types: Vec<FieldType>, sizes: Vec<Vector2D>, fonts: Vec<Option<String>>, tx: oneshot::Sender<DBResult<DataRecord>> },
+    DeleteRecord { x: u32, y: u32, tx: oneshot::Sender<DBResult<DeletedRecord>> },
+    // keeps `keep` newest snapshots
+    CreateSnapshot { keep: usize, tx: oneshot::Sender<DBResult<Snapshot>> },
+    GetSnapshots { tx: oneshot::Sender<DBResult<Vec<Snapshot>>> },
+    // changes since the snapshot are lost
+    RestoreSnapshot { id: u64, tx: oneshot::Sender<DBResult<()>> },
     Sync,
 
     // like "private" ?
     SetModel { model: Model, image: BoxedStorableImage },
 
-    Shutdown
+    // answered when the image is saved
+    Shutdown { tx: oneshot::Sender<()> },
 }
 
 impl Debug for DBMessage {
@@ -85,11 +244,22 @@ impl Debug for DBMessage {
         match self {
             DBMessage::GetModel { .. } => f.debug_struct("DBMessage::GetModel").finish(),
             DBMessage::GetRecords { query, .. } => f.debug_struct("DBMessage::GetRecords").field("query", query).finish(),
-            DBMessage::CloneRecord { x, y, .. } => f.debug_struct("DBMessage::CloneRecord").field("x", x).field("y", y).finish(),
-            DBMessage::SetField { x, y, fi, value, .. } => f.debug_struct("DBMessage::SetField").field("x", x).field("y", y).field("field_index", fi).field("value", value).finish(),
+            DBMessage::GetLegend { .. } => f.debug_struct("DBMessage::GetLegend").finish(),
+            DBMessage::GetReferrers { x, y, .. } => f.debug_struct("DBMessage::GetReferrers").field("x", x).field("y", y).finish(),
+            DBMessage::CloneRecord { x, y, expected_versions, .. } => f.debug_struct("DBMessage::CloneRecord").field("x", x).field("y", y).field("expected_versions", expected_versions).finish(),
+            DBMessage::CreateRecord { column, field_types, sizes, fonts, .. } => f.debug_struct("DBMessage::CreateRecord").field("column", column).field("field_types", field_types).field("sizes", sizes).field("fonts", fonts).finish(),
+            DBMessage::DeleteRecord { x, y, .. } => f.debug_struct("DBMessage::DeleteRecord").field("x", x).field("y", y).finish(),
+            DBMessage::CreateSnapshot { keep, .. } => f.debug_struct("DBMessage::CreateSnapshot").field("keep", keep).finish(),
+            DBMessage::GetSnapshots { .. } => f.debug_struct("DBMessage::GetSnapshots").finish(),
+            DBMessage::RestoreSnapshot { id, .. } => f.debug_struct("DBMessage::RestoreSnapshot").field("id", id).finish(),
+            DBMessage::SetField { x, y, fi, value, client, expected_versions, .. } => f.debug_struct("DBMessage::SetField").field("x", x).field("y", y).field("field_index", fi).field("value", value).field("client", client).field("expected_versions", expected_versions).finish(),
+            DBMessage::SetFields { x, y, values, client, .. } => f.debug_struct("DBMessage::SetFields").field("x", x).field("y", y).field("values", values).field("client", client).finish(),
+            DBMessage::GetHistory { x, y, .. } => f.debug_struct("DBMessage::GetHistory").field("x", x).field("y", y).finish(),
+            DBMessage::RevertField { x, y, version, client, .. } => f.debug_struct("DBMessage::RevertField").field("x", x).field("y", y).field("version", version).field("client", client).finish(),
+            DBMessage::SetReference { x, y, fi, target, .. } => f.debug_struct("DBMessage::SetReference").field("x", x).field("y", y).field("field_index", fi).field("target", target).finish(),
             DBMessage::Sync => f.debug_struct("DBMessage::Sync").finish(),
             DBMessage::SetModel { .. } => f.debug_struct("DBMessage::SetModel").finish(),
-            DBMessage::Shutdown => f.debug_struct("DBMessage::Shutdown").finish(),
+            DBMessage::Shutdown { .. } => f.debug_struct("DBMessage::Shutdown").finish(),
         }
     }
 }
@@ -98,6 +268,14 @@ impl Debug for DBMessage {
 pub enum DBResult<T> {
     Ok(T),
     StillLoading(f32),
+    // the value doesn't fit the field, or the record has no such field
+    Invalid(InvalidValue),
+    // no such record or field, the message tells which
+    NotFound(String),
+    // the query can't be answered as asked, the message tells why
+    BadRequest(String),
+    // the record was changed since the version the client had, current version is given
+    Conflict(u64),
     Err(String),
 }
 
@@ -108,6 +286,18 @@ impl<T> DBResult<T> where T: Debug {
             _ => panic!("Unexpected {:?} instead of value", self)
         }
     }
+
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> DBResult<U> {
+        match self {
+            DBResult::Ok(value) => DBResult::Ok(f(value)),
+            DBResult::StillLoading(progress) => DBResult::StillLoading(progress),
+            DBResult::Invalid(invalid) => DBResult::Invalid(invalid),
+            DBResult::NotFound(message) => DBResult::NotFound(message),
+            DBResult::BadRequest(message) => DBResult::BadRequest(message),
+            DBResult::Conflict(version) => DBResult::Conflict(version),
+            DBResult::Err(error) => DBResult::Err(error),
+        }
+    }
 }
 
 impl<T, E: Into<String>> From<Result<T, E>> for DBResult<T> {
@@ -125,18 +315,44 @@ struct DB {
     image: Option<BoxedStorableImage>,
     model: Option<Model>,
     data_types: DataTypes,
+    history: History,
 
     model_loading_progress: Arc<Mutex<f32>>,
+    events: broadcast::Sender<DBEvent>,
 }
 
 impl DB {
-    fn new<S>(path: S) -> Self where S: Into<String> {
+    fn new<S>(path: S, font: &str, events: broadcast::Sender<DBEvent>) -> Self where S: Into<String> {
+        let path = path.into();
         Self {
-            path: path.into(),
+            history: History::next_to(Path::new(&path)),
+            path,
             image: None,
             model: None,
-            data_types: DataTypes::new(),
+            data_types: DataTypes::new(font),
             model_loading_progress: Arc::new(Mutex::new(0.0)),
+            events,
+        }
+    }
+
+    // Saves changes, pending writes are waited for
+    fn close(&mut self) {
+        if let Some(image) = self.image.as_mut() {
+            if let Err(e) = image.flush() {
+                error!("[{}] Error during saving {}", self.path, e);
+            }
+        }
+    }
+
+    // Reads all records from the image again
+    fn reload(&mut self) {
+        if let Some(image) = self.image.as_mut() {
+            info!("[{}] Reload model", self.path);
+            let mut model = Model::new();
+            load_model_into(&mut model, ImageView::from(image), |_f| { } );
+            self.model = Some(model);
+            info!("[{}] Reloaded.", self.path);
+            notify(&self.events, DBEvent::ModelReloaded);
         }
     }
 
@@ -144,63 +360,64 @@ impl DB {
         //let message_str = format!("{:?}", message);
         //println!("DB[{}]: start processing {}", self.path, message_str);
         match message {
-            DBMessage::Shutdown => {},
+            DBMessage::Shutdown { .. } => {},
             DBMessage::SetModel { model, image } => {
                 self.model = Some(model);
                 self.image = Some(image);
                 *self.model_loading_progress.lock().unwrap() = 1.0;
             }
-            DBMessage::CloneRecord { x, y, tx } => {
+            DBMessage::CloneRecord { x, y, expected_versions, tx } => {
                 let data_types = &self.data_types;
                 match &mut self.model {
                     Some(model) => {
-                        let mut image = self.image.as_mut().unwrap();
+                        let image = self.image.as_mut().unwrap();
+                        if let Some(current) = changed_version(image, model.get_by_id(x, y), expected_versions.as_deref()) {
+                            tx.send(DBResult::Conflict(current)).unwrap();
+                            return;
+                        }
+                        let legend = model.legend(image, data_types);
                         let result: Result<DataRecord, DataError> = model.get_by_id(x, y)
                             .map_or(Result::Err(DataError::NotFound), |r| Result::Ok(r.clone()))
                             .and_then(|rec| {
+                                let column = RGB::from(&rec.column);
+                                let from = Vector2D::new(rec.position.x, rec.position.y.saturating_sub(GLYPH_SIZE));
+                                let size = Vector2D::new(rec.rb_position.x - from.x + 1, rec.rb_position.y - from.y + 1);
+                                let to = allocate_or_grow(model, image, size, &column, Some(rec.position.x))?;
 
-                                let mut x = rec.position.x;
-
-                                let mut y = model.records.iter()
-                                    .filter(|r| r.column == rec.column && r.position.x == x)
-                                    .map(|r| r.rb_position.y)
-                                    .max().unwrap() + 10;
-
-                                while y + rec.rb_position.y - rec.position.y > image.height() {
-                                    x = x + rec.rb_position.x - rec.position.x + 10;
-                                    y = model.records.iter()
-                                        .filter(|r| r.column == rec.column && r.position.x == x)
-                                        .map(|r| r.rb_position.y)
-                                        .max().unwrap_or(rec.position.y) + 10;
-                                }
-
-                                let x_shift = x - rec.position.x;
-                                let y_shift = y - rec.position.y;
-
-                                for xx in rec.position.x..=rec.rb_position.x {
-                                    for yy in rec.position.y..=rec.rb_position.y {
+                                for xx in 0..size.x {
+                                    for yy in 0..size.y {
                                         //todo: not optimal
                                         image.set_pixel(
-                                            xx + x_shift,
-                                            yy + y_shift,
-                                            &image.get_pixel(xx, yy),
+                                            to.x + xx,
+                                            to.y + yy,
+                                            &image.get_pixel(from.x + xx, from.y + yy),
                                         )
                                     }
                                 }
+                                image.set_pixel(to.x, 0, &column);
 
-                                let mut new_record = rec.clone();
-                                let shift_vector = Vector2D::new(x_shift, y_shift);
-                                new_record.position += shift_vector;
-                                new_record.rb_position += shift_vector;
-                                for field in &mut new_record.fields {
-                                    field.type_start += shift_vector;
-                                    field.data_start += shift_vector;
-                                    field.data_end += shift_vector;
-                                }
+                                let new_record = rec.moved(from, to);
                                 model.add_record(&new_record);
 
-                                to_data_record(data_types, &new_record, &mut image)
+                                // only glyphs of reference fields are copied, their lines are drawn from the copy
+                                // again. When a line doesn't fit, the copy is removed rather than left with a lost reference
+                                let id = new_record.position;
+                                for (fi, field) in new_record.fields.iter().enumerate() {
+                                    if let Some(target) = field.ref_to_record {
+                                        if !matches!(set_reference(model, image, id, fi, Some(target)), DBResult::Ok(_)) {
+                                            if let Some(copy) = model.remove_record(id.x, id.y) {
+                                                erase_removed_record(model, image, &copy);
+                                            }
+                                            return Err(DataError::NoRoute(id, target));
+                                        }
+                                    }
+                                }
+
+                                to_data_record(data_types, model.get_by_id(id.x, id.y).unwrap(), image, &legend)
                             });
+                        if let Ok(rec) = &result {
+                            notify(&self.events, DBEvent::RecordCreated { id: rec.id });
+                        }
                         tx.send(result.into()).unwrap();
                     }
                     None => {
@@ -208,35 +425,203 @@ impl DB {
                     }
                 }
             }
+            DBMessage::CreateRecord { column, field_types, sizes, fonts, tx } => {
+                let data_types = &self.data_types;
+                match &mut self.model {
+                    Some(model) => {
+                        let image = self.image.as_mut().unwrap();
+                        let legend = model.legend(image, data_types);
+                        let preferred_x = model.records.iter()
+                            .filter(|r| r.column == column.to_hex_color())
+                            .map(|r| r.position.x)
+                            .min();
+                        let result: Result<DataRecord, DataError> = fonts.iter()
+                            .map(|font| match font {
+                                Some(name) => data_types.font_marker(name).map(Some).ok_or(DataError::Incompatible(
+                                    IncompatibleError::CannotParseValue(format!("Unknown font {}", name))
+                                )),
+                                None => Ok(None)
+                            })
+                            .collect::<Result<Vec<Option<RGB>>, DataError>>()
+                            .and_then(|glyph_colors| {
+                                // image glyph is empty, and empty glyph is read as the type in the top-left corner of the image
+                                let empty_glyph = FieldType(default_type(&ImageView::from(image)));
+                                if empty_glyph != IMAGE_TYPE && field_types.contains(&IMAGE_TYPE) {
+                                    return Err(DataError::Incompatible(IncompatibleError::CannotParseValue(format!(
+                                        "Image fields can't be created, empty type glyph is {} in this image", empty_glyph.name().unwrap_or("unknown")
+                                    ))));
+                                }
+                                let place = allocate_or_grow(model, image, record_size(&sizes), &column, preferred_x)?;
+                                let mut rec = layout_record(Vector2D::new(place.x, place.y + GLYPH_SIZE), &column, &field_types, &sizes)?;
+                                for (field, glyph_color) in rec.fields.iter_mut().zip(glyph_colors) {
+                                    if let Some(glyph_color) = glyph_color {
+                                        field.glyph_color = glyph_color;
+                                    }
+                                }
+                                Ok(rec)
+                            })
+                            .and_then(|rec| {
+                                draw_record(&mut ImageView::from(image), &rec, &column)?;
+                                model.add_record(&rec);
+                                to_data_record(data_types, &rec, image, &legend)
+                            });
+                        let result = match result {
+                            Ok(rec) => {
+                                notify(&self.events, DBEvent::RecordCreated { id: rec.id });
+                                DBResult::Ok(rec)
+                            }
+                            Err(DataError::Incompatible(IncompatibleError::CannotParseValue(message))) => DBResult::BadRequest(message),
+                            Err(error) => DBResult::Err(error.into())
+                        };
+                        tx.send(result).unwrap();
+                    }
+                    None => {
+                        tx.send(DBResult::StillLoading(*self.model_loading_progress.lock().unwrap())).unwrap();
+                    }
+                }
+            }
+            DBMessage::DeleteRecord { x, y, tx } => {
+                match &mut self.model {
+                    Some(model) => {
+                        let image = self.image.as_mut().unwrap();
+                        // history of the record ends with the deletion, a record created later at the same place
+                        // starts its own
+                        let removed = match model.get_by_id(x, y) {
+                            Some(_) => self.history.append_deletion(Vector2D::new(x, y))
+                                .map(|_| model.remove_record(x, y))
+                                .map_err(|error| DataError::from(error).into()),
+                            None => Ok(None)
+                        };
+                        let result: DBResult<DeletedRecord> = match removed {
+                            Ok(Some(rec)) => {
+                                erase_removed_record(model, image, &rec);
+                                DBResult::Ok(DeletedRecord {
+                                    id: rec.position,
+                                    dangling_references: model.detach_references_to(&ImageView::from(image), rec.position),
+                                })
+                            }
+                            Ok(None) => DBResult::NotFound(format!("Record {}/{} not found", x, y)),
+                            Err(error) => DBResult::Err(error)
+                        };
+                        if let DBResult::Ok(deleted) = &result {
+                            notify(&self.events, DBEvent::RecordDeleted { id: deleted.id });
+                            for (id, _) in &deleted.dangling_references {
+                                notify(&self.events, DBEvent::RecordChanged { id: *id });
+                            }
+                        }
+                        tx.send(result).unwrap();
+                    }
+                    None => {
+                        tx.send(DBResult::StillLoading(*self.model_loading_progress.lock().unwrap())).unwrap();
+                    }
+                }
+            }
             DBMessage::GetRecords { query, tx } => {
-                match &self.model {
+                match &mut self.model {
                     Some(model) => {
-                        let mut image = self.image.as_mut().unwrap();
+                        let image = self.image.as_mut().unwrap();
                         let data_types = &self.data_types;
-                        let records_to_return: Vec<&Record> = match query.ids  {
-                            Some(ids) => ids.iter()
-                                .map(|id| model.get_by_id(id.x, id.y))
-                                .filter_map(|o| o)
-                                .collect(),
+                        let legend = model.legend(image, data_types);
+                        let filters = &query.filters;
+                        // sort key of the record, null without ordering
+                        let key = |image: &mut BoxedStorableImage, r: &Record| match &query.order_by {
+                            Some(order_by) => read_field(data_types, image, r, legend.field_index(r, order_by)),
+                            None => DataValue::Null
+                        };
+                        // records are ordered by the key and then by position, so the place of the cursor record
+                        // is known even when it doesn't match the query anymore
+                        let compare = |(a_key, a_id): (&DataValue, Vector2D), (b_key, b_id): (&DataValue, Vector2D)| {
+                            compare_nulls_last(a_key, b_key, query.desc).then((a_id.y, a_id.x).cmp(&(b_id.y, b_id.x)))
+                        };
+                        // only fields used in filters and ordering are decoded to check the record
+                        let records: Vec<&Record> = match &query.ids {
+                            Some(ids) => ids.iter().filter_map(|id| model.get_by_id(id.x, id.y)).collect(),
                             None => model.records
                                 .iter()
                                 .filter(|r| match &query.column {
-                                    Some(c) => r.column.eq(c),
-                                    None => true
+                                    Some(c) => r.column.eq(legend.column_color(c)),
+                                    // legend records are listed only when their column is asked for
+                                    None => r.column != META.to_hex_color()
                                 })
-                                .skip(query.offset.unwrap_or(0) as usize)
-                                .take(query.limit.unwrap_or(model.records.len() as u32) as usize)
                                 .collect()
-
                         };
-
-                        let records_to_return: Result<Vec<DataRecord>, DataError> = records_to_return.iter()
-                            .map(|r| to_data_record(data_types, r, &mut image))
+                        let records: Vec<&Record> = records.into_iter()
+                            .filter(|r| filters.iter().all(|f| f.matches(&read_field(data_types, image, r, legend.field_index(r, &f.field)))))
                             .collect();
-                        match records_to_return {
-                            Ok(records) => tx.send(DBResult::Ok(records)).unwrap(),
-                            Err(error) => tx.send(DBResult::Err(error.into())).unwrap()
+                        let mut matching: Vec<(DataValue, &Record)> = records.into_iter().map(|r| (key(image, r), r)).collect();
+                        // requested ids keep their order unless order_by is given
+                        if query.ids.is_none() || query.order_by.is_some() {
+                            matching.sort_by(|(a, a_rec), (b, b_rec)| compare((a, a_rec.position), (b, b_rec.position)));
                         }
+                        let start = match query.after {
+                            // the cursor has to be among requested ids
+                            Some(id) if query.ids.is_some() => matching.iter()
+                                .position(|(_, r)| r.position == id)
+                                .map(|idx| idx + 1)
+                                .ok_or(format!("Record {}/{} is not found among requested records", id.x, id.y)),
+                            Some(id) => match (&query.order_by, model.get_by_id(id.x, id.y)) {
+                                (Some(_), None) => Err(format!("Record {}/{} is deleted, so its place in the order is unknown", id.x, id.y)),
+                                (_, rec) => {
+                                    let after_key = rec.map_or(DataValue::Null, |rec| key(image, rec));
+                                    Ok(matching.iter()
+                                        .position(|(k, r)| compare((k, r.position), (&after_key, id)) == Ordering::Greater)
+                                        .unwrap_or(matching.len()))
+                                }
+                            },
+                            None => Ok(query.offset.unwrap_or(0) as usize)
+                        };
+
+                        let page: DBResult<RecordsPage> = match start {
+                            Ok(start) => matching.iter()
+                                .skip(start)
+                                .take(query.limit.unwrap_or(matching.len() as u32) as usize)
+                                .map(|(_, r)| to_expanded_record(data_types, model, r, image, &legend, query.expand, &mut vec![]))
+                                .collect::<Result<Vec<DataRecord>, DataError>>()
+                                .map(|records| RecordsPage { records, total: matching.len(), offset: start })
+                                .into(),
+                            Err(error) => DBResult::BadRequest(error)
+                        };
+                        tx.send(page).unwrap();
+                    }
+                    None => {
+                        tx.send(DBResult::StillLoading(*self.model_loading_progress.lock().unwrap())).unwrap();
+                    }
+                }
+            }
+            DBMessage::GetLegend { tx } => {
+                match &mut self.model {
+                    Some(model) => {
+                        let image = self.image.as_mut().unwrap();
+                        tx.send(DBResult::Ok(model.legend(image, &self.data_types))).unwrap();
+                    }
+                    None => {
+                        tx.send(DBResult::StillLoading(*self.model_loading_progress.lock().unwrap())).unwrap();
+                    }
+                }
+            }
+            DBMessage::GetReferrers { x, y, tx } => {
+                match &mut self.model {
+                    Some(model) if model.get_by_id(x, y).is_some() => {
+                        let image = self.image.as_mut().unwrap();
+                        let data_types = &self.data_types;
+                        let legend = model.legend(image, data_types);
+                        let mut grouped: Vec<(Vector2D, Vec<usize>)> = vec![];
+                        for (id, fi) in model.get_referrers(x, y) {
+                            match grouped.iter_mut().find(|(rid, _)| rid == id) {
+                                Some((_, fields)) => fields.push(*fi),
+                                None => grouped.push((*id, vec![*fi]))
+                            }
+                        }
+                        let referrers: Result<Vec<Referrer>, DataError> = grouped.into_iter()
+                            .map(|(id, fields)| {
+                                to_data_record(data_types, model.get_by_id(id.x, id.y).unwrap(), image, &legend)
+                                    .map(|record| Referrer { record, fields })
+                            })
+                            .collect();
+                        tx.send(referrers.into()).unwrap();
+                    }
+                    Some(_) => {
+                        tx.send(DBResult::NotFound(format!("Record {}/{} not found", x, y))).unwrap()
                     }
                     None => {
                         tx.send(DBResult::StillLoading(*self.model_loading_progress.lock().unwrap())).unwrap();
@@ -253,18 +638,137 @@ impl DB {
                     }
                 }
             }
-            DBMessage::SetField { x, y, fi, value, tx } => {
+            DBMessage::SetField { x, y, fi, value, client, expected_versions, tx } => {
+                let image = self.image.as_mut().unwrap();
+                match self.model.as_ref().and_then(|model| model.get_by_id(x, y)) {
+                    Some(rec) if fi as usize >= rec.fields.len() => {
+                        tx.send(DBResult::Invalid(InvalidValue::unknown_field(FieldRef::Index(fi), rec))).unwrap()
+                    }
+                    Some(rec) => {
+                        if let Some(current) = changed_version(image, Some(rec), expected_versions.as_deref()) {
+                            tx.send(DBResult::Conflict(current)).unwrap();
+                            return;
+                        }
+                        let result = set_fields(&self.data_types, image, &mut self.history, rec, vec![(fi, value)], &client)
+                            .map(|_| record_version(image, rec));
+                        if let DBResult::Ok(_) = result {
+                            notify(&self.events, DBEvent::RecordChanged { id: Vector2D::new(x, y) });
+                            self.model.as_mut().unwrap().record_written(Vector2D::new(x, y));
+                        }
+                        tx.send(result).unwrap();
+                    }
+                    _ => {
+                        tx.send(DBResult::NotFound(format!("Record {}/{} not found", x, y))).unwrap()
+                    }
+                }
+            }
+            DBMessage::SetFields { x, y, values, client, tx } => {
                 let image = self.image.as_mut().unwrap();
-                let model = self.model.as_ref();
-                match model
-                    .and_then(|model| model.get_by_id(x, y))
-                    .and_then(|rec| rec.fields.get(fi as usize)) {
-                    Some(field) => {
-                        let mut view = ImageView::new(image, field.data_start, field.data_end);
-                        tx.send(self.data_types.write(&mut view, &field, value).into()).unwrap();
+                let data_types = &self.data_types;
+                let legend = self.model.as_mut().map(|model| model.legend(image, data_types)).unwrap_or_default();
+                match self.model.as_ref().and_then(|model| model.get_by_id(x, y)) {
+                    Some(rec) => {
+                        // all fields are resolved before any is written
+                        let resolved: Result<Vec<(u32, DataValue)>, InvalidValue> = values.into_iter()
+                            .map(|(field, value)| match legend.field_index(rec, &field).filter(|fi| *fi < rec.fields.len()) {
+                                Some(fi) => Ok((fi as u32, value)),
+                                None => Err(InvalidValue::unknown_field(field, rec))
+                            })
+                            .collect();
+                        match resolved {
+                            Ok(values) => {
+                                let result = set_fields(&self.data_types, image, &mut self.history, rec, values, &client);
+                                if let DBResult::Ok(_) = result {
+                                    notify(&self.events, DBEvent::RecordChanged { id: Vector2D::new(x, y) });
+                                    self.model.as_mut().unwrap().record_written(Vector2D::new(x, y));
+                                }
+                                tx.send(result).unwrap()
+                            }
+                            Err(invalid) => tx.send(DBResult::Invalid(invalid)).unwrap()
+                        }
                     }
                     None => {
-                        tx.send(DBResult::Err("Not found".to_string())).unwrap()
+                        tx.send(DBResult::NotFound(format!("Record {}/{} not found", x, y))).unwrap()
+                    }
+                }
+            }
+            DBMessage::GetHistory { x, y, tx } => {
+                let result: Result<Vec<FieldChange>, DataError> = self.history.read(Vector2D::new(x, y)).map_err(DataError::from);
+                tx.send(result.into()).unwrap();
+            }
+            DBMessage::RevertField { x, y, version, client, tx } => {
+                match &mut self.model {
+                    Some(model) => {
+                        let image = self.image.as_mut().unwrap();
+                        let result = revert_field(model, image, &mut self.history, Vector2D::new(x, y), version, &client);
+                        if let DBResult::Ok(_) = result {
+                            model.record_written(Vector2D::new(x, y));
+                            notify(&self.events, DBEvent::RecordChanged { id: Vector2D::new(x, y) });
+                        }
+                        tx.send(result).unwrap();
+                    }
+                    None => {
+                        tx.send(DBResult::StillLoading(*self.model_loading_progress.lock().unwrap())).unwrap();
+                    }
+                }
+            }
+            DBMessage::SetReference { x, y, fi, target, tx } => {
+                match &mut self.model {
+                    Some(model) => {
+                        let image = self.image.as_mut().unwrap();
+                        let result = set_reference(model, image, Vector2D::new(x, y), fi as usize, target);
+                        if let DBResult::Ok(_) = result {
+                            notify(&self.events, DBEvent::RecordChanged { id: Vector2D::new(x, y) });
+                        }
+                        tx.send(result).unwrap();
+                    }
+                    None => {
+                        tx.send(DBResult::StillLoading(*self.model_loading_progress.lock().unwrap())).unwrap();
+                    }
+                }
+            }
+            DBMessage::CreateSnapshot { keep, tx } => {
+                match self.image.as_mut() {
+                    Some(image) => {
+                        let path = Path::new(&self.path);
+                        let result: Result<Snapshot, DataError> = image.flush()
+                            .and_then(|_| create_snapshot(path))
+                            .and_then(|snapshot| {
+                                for removed in prune_snapshots(path, keep)? {
+                                    info!("[{}] Removed snapshot {}", self.path, removed.id);
+                                }
+                                Ok(snapshot)
+                            })
+                            .map_err(DataError::from);
+                        tx.send(result.into()).unwrap();
+                    }
+                    None => {
+                        tx.send(DBResult::StillLoading(*self.model_loading_progress.lock().unwrap())).unwrap();
+                    }
+                }
+            }
+            DBMessage::GetSnapshots { tx } => {
+                let result: Result<Vec<Snapshot>, DataError> = list_snapshots(Path::new(&self.path)).map_err(DataError::from);
+                tx.send(result.into()).unwrap();
+            }
+            DBMessage::RestoreSnapshot { id, tx } => {
+                match self.image.as_mut() {
+                    Some(image) => {
+                        // pending writes would go over the restored file
+                        match image.flush().and_then(|_| restore_snapshot(Path::new(&self.path), id)) {
+                            Ok(()) => {
+                                info!("[{}] Restored snapshot {}", self.path, id);
+                                self.image = Some(load_image(&self.path));
+                                self.reload();
+                                tx.send(DBResult::Ok(())).unwrap();
+                            }
+                            // unknown id
+                            Err(e) if e.kind() == ErrorKind::NotFound => tx.send(DBResult::NotFound(e.to_string())).unwrap(),
+                            Err(e) => tx.send(DBResult::Err(DataError::from(e).into())).unwrap(),
+                        }
+                    }
+                    None => {
+                        tx.send(DBResult::StillLoading(*self.model_loading_progress.lock().unwrap())).unwrap();
                     }
                 }
             }
@@ -272,13 +776,17 @@ impl DB {
                 match self.image.as_mut() {
                     None => {}
                     Some(image) => {
-                        if let SyncResponse::Reloaded = image.sync().unwrap() {
-                            info!("[{}] Reload model", self.path);
-                            let mut model = Model::new();
-                            load_model_into(&mut model, ImageView::from(image), |_f| { } );
-                            self.model = Some(model);
-                            info!("[{}] Reloaded.", self.path);
-
+                        match image.sync().unwrap() {
+                            SyncResponse::Reloaded => self.reload(),
+                            SyncResponse::Changed(tiles) => {
+                                if let Some(model) = self.model.as_mut() {
+                                    info!("[{}] Reload {} changed tiles", self.path, tiles.len());
+                                    reload_tiles_into(model, ImageView::from(image), &tiles);
+                                    info!("[{}] Reloaded.", self.path);
+                                    notify(&self.events, DBEvent::ModelReloaded);
+                                }
+                            }
+                            SyncResponse::Ok => {}
                         }
                     }
                 }
@@ -289,69 +797,398 @@ impl DB {
     }
 }
 
-fn to_data_record(data_types: &DataTypes, rec: &Record, image: &mut BoxedStorableImage) -> Result<DataRecord, DataError> {
+// Blanks pixels of the record already removed from the model, and the column marker if it was the last record of the column
+fn erase_removed_record(model: &Model, image: &mut BoxedStorableImage, rec: &Record) {
+    let others = &model.records;
+    let mut view = ImageView::from(image);
+    erase_record(&mut view, rec, |x, y| others.iter().any(|r| r.contains_with_glyphs(x, y)));
+    if !others.iter().any(|r| r.position.x == rec.position.x) {
+        view.set_pixel(rec.position.x, 0, BLANK).unwrap();
+    }
+}
+
+// Finds free place for the area of `size`. When there is none, the image grows to the bottom
+// (and to the right if the area is wider than the image) and the search is repeated.
+fn allocate_or_grow(model: &Model, image: &mut BoxedStorableImage, size: Vector2D, column: &RGB, preferred_x: Option<u32>) -> Result<Vector2D, DataError> {
+    let free_space = FreeSpace::new(&model.records);
+    match free_space.allocate(&ImageView::from(image), size, column, preferred_x) {
+        Err(DataError::NoSpace) => {
+            let width = image.width().max(size.x + 2 * MARGIN);
+            let height = image.height() + (size.y + 2 * MARGIN).max(image.height() / 4);
+            info!("No space for {}x{}, resize image to {}x{}", size.x, size.y, width, height);
+            image.resize(width, height)?;
+            free_space.allocate(&ImageView::from(image), size, column, preferred_x)
+        }
+        result => result
+    }
+}
+
+// Writes all values or none of them: if some value is rejected, already written fields get their pixels back.
+// Written fields are added to the history, a write is not made if it can't be added there.
+fn set_fields(data_types: &DataTypes, image: &mut BoxedStorableImage, history: &mut History, rec: &Record, values: Vec<(u32, DataValue)>, client: &str) -> DBResult<()> {
+    let mut conformed = vec![];
+    for (fi, value) in values {
+        let field = match rec.fields.get(fi as usize) {
+            Some(field) => field,
+            None => return DBResult::Invalid(InvalidValue::unknown_field(FieldRef::Index(fi), rec))
+        };
+        match data_types.conform(value, field.field_type) {
+            Ok(value) => conformed.push((fi, field, value)),
+            Err(value) => return DBResult::Invalid(InvalidValue {
+                field: FieldRef::Index(fi),
+                expected: field.field_type.name().unwrap_or("unknown").to_string(),
+                given: data_types.get_preferred_type(&value).and_then(|t| t.name()).unwrap_or("unknown").to_string(),
+            })
+        }
+    }
+    let mut snapshots = vec![];
+    for (fi, field, value) in conformed {
+        let mut view = ImageView::new(image, field.data_start, field.data_end);
+        snapshots.push((fi, field, view.snapshot()));
+        if let Err(error) = data_types.write(&mut view, field, value) {
+            restore_fields(image, &snapshots);
+            return match error {
+                DataError::Incompatible(error) => DBResult::Invalid(InvalidValue::new(fi, field.field_type, error)),
+                error => DBResult::Err(error.into())
+            };
+        }
+    }
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    let changes: Vec<FieldChange> = snapshots.iter()
+        .map(|(fi, field, old_pixels)| FieldChange {
+            version: 0,
+            record: rec.position,
+            field: *fi,
+            time,
+            client: client.to_string(),
+            data_start: field.data_start,
+            data_end: field.data_end,
+            old_pixels: old_pixels.clone(),
+            new_pixels: ImageView::new(image, field.data_start, field.data_end).snapshot(),
+        })
+        // writing the same value again is not a change
+        .filter(|change| change.old_pixels != change.new_pixels)
+        .collect();
+    if let Err(error) = history.append(&changes) {
+        restore_fields(image, &snapshots);
+        return DBResult::Err(DataError::from(error).into());
+    }
+    DBResult::Ok(())
+}
+
+// Fields may share data area (references), so they are restored in reverse order
+fn restore_fields(image: &mut BoxedStorableImage, snapshots: &[(u32, &Field, Vec<RGB>)]) {
+    for (_, field, pixels) in snapshots.iter().rev() {
+        ImageView::new(image, field.data_start, field.data_end).restore(pixels);
+    }
+}
+
+// Puts back pixels the field had before the change of `version`, that is a new change in the history.
+// The field should have the same data area as at the time of the change.
+fn revert_field(model: &Model, image: &mut BoxedStorableImage, history: &mut History, id: Vector2D, version: u64, client: &str) -> DBResult<()> {
+    let change = match history.read(id) {
+        Ok(changes) => match changes.into_iter().find(|change| change.version == version) {
+            Some(change) => change,
+            None => return DBResult::NotFound(format!("Version {} of record {}/{} not found", version, id.x, id.y))
+        },
+        Err(error) => return DBResult::Err(DataError::from(error).into())
+    };
+    let field = match model.get_by_id(id.x, id.y).and_then(|rec| rec.fields.get(change.field as usize)) {
+        Some(field) => field,
+        None => return DBResult::NotFound(format!("Field {} of record {}/{} not found", change.field, id.x, id.y))
+    };
+    if field.data_start != change.data_start || field.data_end != change.data_end {
+        return DBResult::Err(format!("Field {} of record {}/{} was moved or resized after version {}", change.field, id.x, id.y, version));
+    }
+    let mut view = ImageView::new(image, field.data_start, field.data_end);
+    let revert = FieldChange {
+        time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+        client: client.to_string(),
+        old_pixels: view.snapshot(),
+        new_pixels: change.old_pixels.clone(),
+        ..change
+    };
+    if revert.old_pixels == revert.new_pixels {
+        return DBResult::Ok(());
+    }
+    // the history goes first, so the revert is not made if it can't be added there
+    if let Err(error) = history.append(std::slice::from_ref(&revert)) {
+        return DBResult::Err(DataError::from(error).into());
+    }
+    view.restore(&revert.new_pixels);
+    DBResult::Ok(())
+}
+
+// Erases the line of the reference field and draws a new one to the target record, if any.
+// When there is no place for the new line, the old one is kept.
+fn set_reference(model: &mut Model, image: &mut BoxedStorableImage, id: Vector2D, fi: usize, target: Option<Vector2D>) -> DBResult<()> {
+    let rec = match model.get_by_id(id.x, id.y) {
+        Some(rec) => rec,
+        None => return DBResult::NotFound(format!("Record {}/{} not found", id.x, id.y))
+    };
+    let field = match rec.fields.get(fi) {
+        Some(field) => field,
+        None => return DBResult::NotFound(format!("Field {} of record {}/{} not found", fi, id.x, id.y))
+    };
+    if !field.is_reference() {
+        return DBResult::Invalid(InvalidValue {
+            field: FieldRef::Index(fi as u32),
+            expected: "reference".to_string(),
+            given: field.field_type.name().unwrap_or("unknown").to_string(),
+        });
+    }
+    let target_rec = match target {
+        Some(t) if t == id => return DBResult::BadRequest("Record cannot reference itself".to_string()),
+        Some(t) => match model.get_by_id(t.x, t.y) {
+            Some(target_rec) => Some(target_rec),
+            None => return DBResult::NotFound(format!("Record {}/{} not found", t.x, t.y))
+        },
+        None => None
+    };
+
+    let mut view = ImageView::from(image);
+    let color = view.get_pixel(line_start(field).x, line_start(field).y);
+    let old_line: Vec<(Vector2D, RGB)> = trace_own_reference_line(&view, &model.records, rec, field).into_iter()
+        .map(|p| (p, view.get_pixel(p.x, p.y)))
+        .collect();
+    for (p, _) in &old_line {
+        view.set_pixel(p.x, p.y, BLANK).unwrap();
+    }
+    if let Some(target_rec) = target_rec {
+        match route_reference_line(&view, &model.records, rec, field, target_rec) {
+            Some(line) => {
+                for p in line {
+                    view.set_pixel(p.x, p.y, color).unwrap();
+                }
+            }
+            None => {
+                for (p, pixel) in old_line {
+                    view.set_pixel(p.x, p.y, pixel).unwrap();
+                }
+                return DBResult::Err(DataError::NoRoute(id, target_rec.position).into());
+            }
+        }
+    }
+    model.set_reference(&view, id, fi, target);
+    DBResult::Ok(())
+}
+
+// Value of the field or null if there is no such field or it cannot be read
+fn read_field(data_types: &DataTypes, image: &mut BoxedStorableImage, rec: &Record, fi: Option<usize>) -> DataValue {
+    match fi.and_then(|fi| rec.fields.get(fi)) {
+        Some(field) => {
+            let view = ImageView::new(image, field.data_start, field.data_end);
+            data_types.read(&view, field).unwrap_or(DataValue::Null)
+        }
+        None => DataValue::Null
+    }
+}
+
+fn to_data_record(data_types: &DataTypes, rec: &Record, image: &mut BoxedStorableImage, legend: &Legend) -> Result<DataRecord, DataError> {
     let mut fields = vec![];
 
-    for field in &rec.fields {
+    for (fi, field) in rec.fields.iter().enumerate() {
         let view = ImageView::new(image, field.data_start, field.data_end);
         fields.push(DataFieldValue {
             value: data_types.read(&view, field)?,
             reference: field.ref_to_record,
+            name: legend.field_name(&rec.column, fi).map(|name| name.to_string()),
+            record: None,
         })
     }
 
     Ok(DataRecord {
         id: rec.position,
         column: rec.column.clone(),
+        column_name: legend.column_name(&rec.column).map(|name| name.to_string()),
         fields,
+        version: record_version(image, rec),
     })
 }
 
+// Hash of the record pixels, type glyphs above its blocks included, and of data areas of referenced records,
+// any write to its fields changes it
+fn record_version(image: &BoxedStorableImage, rec: &Record) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let mut hash_area = |from: Vector2D, to: Vector2D| {
+        for y in from.y..=to.y {
+            for x in from.x..=to.x {
+                image.get_pixel(x, y).hash(&mut hasher);
+            }
+        }
+    };
+    hash_area(Vector2D::new(rec.position.x, rec.position.y.saturating_sub(GLYPH_SIZE)), rec.rb_position);
+    for field in rec.fields.iter().filter(|field| field.ref_to_record.is_some()) {
+        hash_area(field.data_start, field.data_end);
+    }
+    hasher.finish()
+}
+
+// Current version of the record if it is none of the expected ones, None when nothing is expected
+fn changed_version(image: &BoxedStorableImage, rec: Option<&Record>, expected_versions: Option<&[u64]>) -> Option<u64> {
+    let expected = expected_versions?;
+    let current = record_version(image, rec?);
+    if !expected.contains(&current) { Some(current) } else { None }
+}
+
+// `path` holds ids of records being expanded, from the top one
+fn to_expanded_record(data_types: &DataTypes, model: &Model, rec: &Record, image: &mut BoxedStorableImage, legend: &Legend, depth: u32, path: &mut Vec<Vector2D>) -> Result<DataRecord, DataError> {
+    let mut data_record = to_data_record(data_types, rec, image, legend)?;
+    if depth == 0 {
+        return Ok(data_record);
+    }
+    path.push(rec.position);
+    for field in data_record.fields.iter_mut() {
+        let target = field.reference
+            .filter(|id| !path.contains(id))
+            .and_then(|id| model.get_by_id(id.x, id.y));
+        if let Some(target) = target {
+            field.record = Some(Box::new(to_expanded_record(data_types, model, target, image, legend, depth - 1, path)?));
+        }
+    }
+    path.pop();
+    Ok(data_record)
+}
+
+// Sending fails only when nobody is subscribed, that's fine
+fn notify(events: &broadcast::Sender<DBEvent>, event: DBEvent) {
+    let _ = events.send(event);
+}
 
 #[derive(Clone)]
 pub struct DBHandle {
     tx: UnboundedSender<DBMessage>,
+    events: broadcast::Sender<DBEvent>,
 }
 
 impl DBHandle {
     pub fn run_in_background(path: &str) -> DBHandle {
-        let mut db = DB::new(path);
+        DBHandle::run_in_background_with_font(path, DEFAULT_FONT)
+    }
+
+    // `font` is used for string fields which type glyph has no font marker color
+    pub fn run_in_background_with_font(path: &str, font: &str) -> DBHandle {
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        let mut db = DB::new(path, font, events.clone());
         let (tx, mut rx) = unbounded_channel();
         let path = path.to_string();
         let async_tx = tx.clone();
         tokio::spawn(async move {
             do_load_async(path.as_str(), async_tx, db.model_loading_progress.clone());
             while let Some(message) = rx.recv().await {
-                if let DBMessage::Shutdown = message {
-                    break;
+                match message {
+                    DBMessage::Shutdown { tx } => {
+                        db.close();
+                        let _ = tx.send(());
+                        break;
+                    }
+                    message => db.handle(message).await
                 }
-                db.handle(message).await
             }
         });
-        DBHandle { tx }
+        DBHandle { tx, events }
+    }
+
+    // Events of changes made after the call
+    pub fn subscribe(&self) -> broadcast::Receiver<DBEvent> {
+        self.events.subscribe()
     }
 
-    pub async fn get_records(&self, query: DBQuery) -> DBResult<Vec<DataRecord>> {
+    pub async fn get_records(&self, query: DBQuery) -> DBResult<RecordsPage> {
         let (tx, rx) = oneshot::channel();
         self.tx.send(DBMessage::GetRecords { query, tx }).unwrap();
         rx.await.unwrap()
     }
 
+    pub async fn get_legend(&self) -> DBResult<Legend> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(DBMessage::GetLegend { tx }).unwrap();
+        rx.await.unwrap()
+    }
+
+    // records with fields referencing the record
+    pub async fn get_referrers(&self, x: u32, y: u32) -> DBResult<Vec<Referrer>> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(DBMessage::GetReferrers { x, y, tx }).unwrap();
+        rx.await.unwrap()
+    }
+
     pub async fn get_model(&self) -> DBResult<Model> {
         let (tx, rx) = oneshot::channel();
         self.tx.send(DBMessage::GetModel { tx }).unwrap();
         rx.await.unwrap()
     }
 
-    pub async fn set_field(&self, x: u32, y: u32, fi: u32, value: DataValue) -> DBResult<()> {
+    // client identifies the writer in the history, Conflict is returned when the record has none of
+    // expected_versions, new version of the record otherwise
+    pub async fn set_field(&self, x: u32, y: u32, fi: u32, value: DataValue, client: String, expected_versions: Option<Vec<u64>>) -> DBResult<u64> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(DBMessage::SetField { x, y, fi, value, client, expected_versions, tx}).unwrap();
+        rx.await.unwrap()
+    }
+
+    // values are (field index or name, value) pairs, either all of them are written or none
+    pub async fn set_fields(&self, x: u32, y: u32, values: Vec<(FieldRef, DataValue)>, client: String) -> DBResult<()> {
         let (tx, rx) = oneshot::channel();
-        self.tx.send(DBMessage::SetField { x, y, fi, value, tx}).unwrap();
+        self.tx.send(DBMessage::SetFields { x, y, values, client, tx }).unwrap();
         rx.await.unwrap()
     }
 
-    pub async fn clone_record(&self, x: u32, y: u32) -> DBResult<DataRecord> {
+    // changes of the record fields, oldest first
+    pub async fn get_history(&self, x: u32, y: u32) -> DBResult<Vec<FieldChange>> {
         let (tx, rx) = oneshot::channel();
-        self.tx.send(DBMessage::CloneRecord { x, y, tx }).unwrap();
+        self.tx.send(DBMessage::GetHistory { x, y, tx }).unwrap();
+        rx.await.unwrap()
+    }
+
+    // undoes the change of this version and later changes of the same field
+    pub async fn revert_field(&self, x: u32, y: u32, version: u64, client: String) -> DBResult<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(DBMessage::RevertField { x, y, version, client, tx }).unwrap();
+        rx.await.unwrap()
+    }
+
+    // points the reference field to the target record by drawing the line, None removes the line
+    pub async fn set_reference(&self, x: u32, y: u32, fi: u32, target: Option<Vector2D>) -> DBResult<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(DBMessage::SetReference { x, y, fi, target, tx }).unwrap();
+        rx.await.unwrap()
+    }
+
+    // Conflict is returned when the record has none of expected_versions
+    pub async fn clone_record(&self, x: u32, y: u32, expected_versions: Option<Vec<u64>>) -> DBResult<DataRecord> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(DBMessage::CloneRecord { x, y, expected_versions, tx }).unwrap();
+        rx.await.unwrap()
+    }
+
+    pub async fn create_record(&self, column: RGB, field_types: Vec<FieldType>, sizes: Vec<Vector2D>, fonts: Vec<Option<String>>) -> DBResult<DataRecord> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(DBMessage::CreateRecord { column, field_types, sizes, fonts, tx }).unwrap();
+        rx.await.unwrap()
+    }
+
+    pub async fn delete_record(&self, x: u32, y: u32) -> DBResult<DeletedRecord> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(DBMessage::DeleteRecord { x, y, tx }).unwrap();
+        rx.await.unwrap()
+    }
+
+    pub async fn create_snapshot(&self, keep: usize) -> DBResult<Snapshot> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(DBMessage::CreateSnapshot { keep, tx }).unwrap();
+        rx.await.unwrap()
+    }
+
+    pub async fn get_snapshots(&self) -> DBResult<Vec<Snapshot>> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(DBMessage::GetSnapshots { tx }).unwrap();
+        rx.await.unwrap()
+    }
+
+    pub async fn restore_snapshot(&self, id: u64) -> DBResult<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(DBMessage::RestoreSnapshot { id, tx }).unwrap();
         rx.await.unwrap()
     }
 
@@ -359,8 +1196,11 @@ impl DBHandle {
         self.tx.send(DBMessage::Sync).unwrap();
     }
 
-    pub fn shutdown(&self) {
-        self.tx.send(DBMessage::Shutdown).unwrap();
+    // Completes when changes are saved
+    pub async fn shutdown(&self) {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(DBMessage::Shutdown { tx }).unwrap();
+        rx.await.unwrap()
     }
 
 }
@@ -48,6 +48,24 @@ impl<'a> ImageView<'a> {
         self.fill(&RGB::new(255, 255, 255))
     }
 
+    // Pixels row by row, to be put back with `restore`
+    pub(crate) fn snapshot(&self) -> Vec<RGB> {
+        let mut pixels = Vec::with_capacity((self.width * self.height) as usize);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                pixels.push(self.get_pixel(x, y));
+            }
+        }
+        pixels
+    }
+
+    pub(crate) fn restore(&mut self, pixels: &[RGB]) {
+        for (idx, pixel) in pixels.iter().enumerate() {
+            let idx = idx as u32;
+            self.set_pixel(idx % self.width, idx / self.width, *pixel).unwrap();
+        }
+    }
+
     pub fn optimize(&self) {
         self.image.optimize();
     }
@@ -62,6 +80,8 @@ impl<'a> ImageView<'a> {
 pub enum SyncResponse {
     Ok,
     Reloaded,
+    // reloaded with the same size, pixels differ only in these (column, row) TILE_SIZE tiles
+    Changed(Vec<(u32, u32)>),
 }
 
 
@@ -74,8 +94,15 @@ pub trait StorableImage: Debug {
 
     fn height(&self) -> u32;
 
+    // Enlarges the canvas to the right and to the bottom, so coordinates of existing pixels stay the same.
+    // New pixels are blank.
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), std::io::Error>;
+
     fn sync(&mut self) -> Result<SyncResponse, std::io::Error>;
 
+    // Writes changes and waits until they are on disk
+    fn flush(&mut self) -> Result<(), std::io::Error>;
+
     fn optimize(&self);
 
     fn get_base64(&self, x: u32, y: u32, width: u32, height: u32) -> String;
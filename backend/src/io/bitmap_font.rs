@@ -2,6 +2,7 @@ use image::{DynamicImage, GenericImageView};
 use std::collections::HashMap;
 use crate::image::{ImageView};
 use crate::model::colors::RGB;
+use crate::model::model::{DataError, IncompatibleError};
 
 
 pub struct BitmapFont {
@@ -10,16 +11,22 @@ pub struct BitmapFont {
     pub(crate) char_dimensions: (u32, u32),
     alphabet: Vec<char>,
 
-    mapping: HashMap<u32, char>,
+    mapping: HashMap<u128, char>,
     chars_to_idx: HashMap<char, usize>,
 }
 
 impl BitmapFont {
     pub fn open3x5(path: &str, alphabet: &str) -> BitmapFont {
+        BitmapFont::open(path, (3, 5), alphabet)
+    }
+
+    // Glyphs are placed left to right with 1 pixel spacing, each glyph is stored in the key bits, so up to 128 pixels
+    pub fn open(path: &str, char_dimensions: (u32, u32), alphabet: &str) -> BitmapFont {
+        assert!(char_dimensions.0 * char_dimensions.1 <= 128, "Glyph {:?} is too big", char_dimensions);
         let mut bf = BitmapFont {
             image: image::open(path).unwrap(),
             spacing: 1,
-            char_dimensions: (3, 5),
+            char_dimensions,
             alphabet: alphabet.chars().collect(),
             mapping: HashMap::new(),
             chars_to_idx: HashMap::new(),
@@ -33,7 +40,7 @@ impl BitmapFont {
         let mut char_idx = 0;
         for x in (0..self.image.width()).step_by((self.char_dimensions.0 + self.spacing) as usize) {
             for y in (0..self.image.height()).step_by((self.char_dimensions.1 + self.spacing) as usize) {
-                let mut key: u32 = 0;
+                let mut key: u128 = 0;
                 for xx in x..x + self.char_dimensions.0 {
                     for yy in y..y + self.char_dimensions.1 {
                         key = key << 1;
@@ -53,7 +60,7 @@ impl BitmapFont {
     }
 
     pub fn get_char(&self, image: &ImageView, x: u32, y: u32) -> Option<char> {
-        let mut key: u32 = 0;
+        let mut key: u128 = 0;
         for xx in x..x + self.char_dimensions.0 {
             for yy in y..y + self.char_dimensions.1 {
                 key = key << 1;
@@ -65,23 +72,252 @@ impl BitmapFont {
         self.mapping.get(&key).map(|c| *c)
     }
 
-    pub fn put_string(&self, image: &mut ImageView, x: u32, y: u32, str: &str) {
-        let mut cx = x;
+    // Distance between left sides of neighbour characters
+    pub fn char_step(&self) -> u32 {
+        self.char_dimensions.0 + self.spacing
+    }
+
+    // Distance between tops of neighbour lines, lines are separated a bit more than characters
+    pub fn line_step(&self) -> u32 {
+        self.char_dimensions.1 + 2 * self.spacing
+    }
+
+    // Number of characters in line and number of lines fitting into the area, text starts at (x, y)
+    pub fn capacity(&self, x: u32, y: u32, width: u32, height: u32) -> (u32, u32) {
+        let fit = |from: u32, size: u32, glyph: u32, step: u32| {
+            if from + glyph > size { 0 } else { (size - from - glyph) / step + 1 }
+        };
+        (
+            fit(x, width, self.char_dimensions.0, self.char_step()),
+            fit(y, height, self.char_dimensions.1, self.line_step())
+        )
+    }
+
+    // Splits string into lines of `columns` characters on spaces, only words longer than a line are broken.
+    // Space is dropped at the end of a line ended before the right edge, but starts the next line after a full one,
+    // so reading puts spaces back only after short lines (see ABC data type).
+    fn wrap(str: &str, columns: usize) -> Vec<Vec<char>> {
+        let mut lines = vec![];
+        let mut line: Vec<char> = vec![];
+        for (idx, word) in str.split(' ').enumerate() {
+            let word: Vec<char> = word.chars().collect();
+            let space = idx > 0;
+            if line.len() + space as usize + word.len() <= columns {
+                if space {
+                    line.push(' ');
+                }
+                line.extend(word);
+                continue;
+            }
+            if space {
+                let full = line.len() >= columns;
+                lines.push(std::mem::take(&mut line));
+                if full {
+                    line.push(' ');
+                }
+            }
+            for chr in word {
+                if line.len() >= columns {
+                    lines.push(std::mem::take(&mut line));
+                }
+                line.push(chr);
+            }
+        }
+        lines.push(line);
+        lines
+    }
+
+    // Lines of the string as they will be put into the area, error tells the capacity if string doesn't fit
+    // or names characters missing in the font. Spaces are left blank, line breaks are not supported as
+    // they can't be told from wrapping when the string is read.
+    pub fn layout(&self, x: u32, y: u32, width: u32, height: u32, str: &str) -> Result<Vec<Vec<char>>, DataError> {
+        let mut unsupported: Vec<char> = vec![];
         for chr in str.chars() {
-            if let Some(idc) = self.chars_to_idx.get(&chr) {
-                let char_idx = *idc as u32;
-                let font_x = char_idx * self.char_dimensions.0 + (char_idx * self.spacing);
-                for dx in 0..self.char_dimensions.0 {
-                    for dy in 0..self.char_dimensions.1 {
-                        if let Result::Err(_) = image.set_pixel(cx + dx, y + dy, self.image.get_pixel(font_x + dx, dy)) {
-                            log::error!("Cannot put string {} at {},{}: too long", str, x, y);
-                            break //todo: return error
+            if chr != ' ' && !self.chars_to_idx.contains_key(&chr) && !unsupported.contains(&chr) {
+                unsupported.push(chr);
+            }
+        }
+        if !unsupported.is_empty() {
+            return Err(DataError::Incompatible(IncompatibleError::CannotParseValue(
+                format!("Characters {:?} are not supported by the font", unsupported.iter().collect::<String>())
+            )));
+        }
+        let (columns, rows) = self.capacity(x, y, width, height);
+        let lines = BitmapFont::wrap(str, columns as usize);
+        if lines.len() > rows as usize || (columns == 0 && !str.is_empty()) {
+            return Err(DataError::Incompatible(IncompatibleError::TooLong {
+                length: str.chars().count(),
+                capacity: (columns * rows) as usize,
+            }));
+        }
+        Ok(lines)
+    }
+
+    pub fn put_string(&self, image: &mut ImageView, x: u32, y: u32, str: &str) -> Result<(), DataError> {
+        let lines = self.layout(x, y, image.width, image.height, str)?;
+        for (line_idx, line) in lines.iter().enumerate() {
+            let cy = y + line_idx as u32 * self.line_step();
+            for (col, chr) in line.iter().enumerate() {
+                // layout leaves only spaces without glyph
+                if let Some(idc) = self.chars_to_idx.get(chr) {
+                    let char_idx = *idc as u32;
+                    let font_x = char_idx * self.char_step();
+                    let cx = x + col as u32 * self.char_step();
+                    for dx in 0..self.char_dimensions.0 {
+                        for dy in 0..self.char_dimensions.1 {
+                            image.set_pixel(cx + dx, cy + dy, self.image.get_pixel(font_x + dx, dy))
+                                .map_err(|_| DataError::Incompatible(IncompatibleError::InvalidSize))?;
                         }
                     }
                 }
             }
-            cx += self.char_dimensions.0 + self.spacing;
         }
+        Ok(())
     }
 }
 
+pub const DEFAULT_FONT: &str = "3x5";
+
+// Fonts available for ABC fields. A field uses the font whose marker color its type glyph is drawn with,
+// fields with plain glyph use the default font of the database.
+pub struct FontRegistry {
+    fonts: Vec<(String, Option<RGB>, BitmapFont)>,
+    default_idx: usize,
+}
+
+impl FontRegistry {
+    pub fn new() -> FontRegistry {
+        let mut registry = FontRegistry { fonts: vec![], default_idx: 0 };
+        registry.register(DEFAULT_FONT, RGB::from_hex_color("#FF0000"),
+                          BitmapFont::open3x5("font1.png", "ABCDEFGHIJKLMNOPQRSTUVWXYZ1234567890_+-*"));
+        registry.register("5x7", RGB::from_hex_color("#0000FF"),
+                          BitmapFont::open("font2.png", (5, 7), r##"!"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\]^_`abcdefghijklmnopqrstuvwxyz{|}~"##));
+        registry
+    }
+
+    pub fn register(&mut self, name: &str, marker: Option<RGB>, font: BitmapFont) {
+        self.fonts.push((name.to_string(), marker, font));
+    }
+
+    pub fn set_default(&mut self, name: &str) -> bool {
+        match self.fonts.iter().position(|(n, _, _)| n == name) {
+            Some(idx) => {
+                self.default_idx = idx;
+                true
+            }
+            None => false
+        }
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.fonts.iter().map(|(n, _, _)| n.as_str()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&BitmapFont> {
+        self.fonts.iter().find(|(n, _, _)| n == name).map(|(_, _, f)| f)
+    }
+
+    pub fn get_marker(&self, name: &str) -> Option<RGB> {
+        self.fonts.iter().find(|(n, _, _)| n == name).and_then(|(_, marker, _)| *marker)
+    }
+
+    pub fn get_default(&self) -> &BitmapFont {
+        &self.fonts[self.default_idx].2
+    }
+
+    pub fn get_by_marker(&self, color: &RGB) -> &BitmapFont {
+        self.fonts.iter()
+            .find(|(_, marker, _)| marker.as_ref() == Some(color))
+            .map_or(self.get_default(), |(_, _, f)| f)
+    }
+}
+
+impl Default for FontRegistry {
+    fn default() -> Self {
+        FontRegistry::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::image_io::blank_image;
+    use crate::model::model::Vector2D;
+
+    fn font() -> BitmapFont {
+        BitmapFont::open3x5(concat!(env!("CARGO_MANIFEST_DIR"), "/../font1.png"), "ABCDEFGHIJKLMNOPQRSTUVWXYZ1234567890_+-*")
+    }
+
+    fn lines(lines: Vec<Vec<char>>) -> Vec<String> {
+        lines.into_iter().map(|line| line.into_iter().collect()).collect()
+    }
+
+    #[test]
+    fn wraps_on_spaces() {
+        assert_eq!(lines(BitmapFont::wrap("AB CD EF", 6)), vec!["AB CD", "EF"]);
+        assert_eq!(lines(BitmapFont::wrap("ABC DEF", 7)), vec!["ABC DEF"]);
+        assert_eq!(lines(BitmapFont::wrap("", 5)), vec![""]);
+    }
+
+    #[test]
+    fn breaks_only_long_words() {
+        assert_eq!(lines(BitmapFont::wrap("ABCDEFGH", 3)), vec!["ABC", "DEF", "GH"]);
+        assert_eq!(lines(BitmapFont::wrap("A BCDEFGH", 3)), vec!["A", "BCD", "EFG", "H"]);
+    }
+
+    #[test]
+    fn space_after_full_line_starts_the_next_one() {
+        assert_eq!(lines(BitmapFont::wrap("ABC DE", 3)), vec!["ABC", " DE"]);
+        assert_eq!(lines(BitmapFont::wrap("ABC DEF", 3)), vec!["ABC", " DE", "F"]);
+    }
+
+    #[test]
+    fn line_breaks_are_rejected() {
+        match font().layout(1, 1, 13, 15, "AB\nCD") {
+            Err(DataError::Incompatible(IncompatibleError::CannotParseValue(message))) => assert!(message.contains("\\n"), "{}", message),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn layout_checks_characters_and_capacity() {
+        let font = font();
+        // 3 characters in 2 lines
+        assert_eq!(font.capacity(1, 1, 13, 15), (3, 2));
+        assert_eq!(lines(font.layout(1, 1, 13, 15, "AB CD").unwrap()), vec!["AB", "CD"]);
+        match font.layout(1, 1, 13, 15, "Ab~") {
+            Err(DataError::Incompatible(IncompatibleError::CannotParseValue(message))) => assert!(message.contains("b~"), "{}", message),
+            other => panic!("unexpected {:?}", other),
+        }
+        match font.layout(1, 1, 13, 15, "AB CD EF") {
+            Err(DataError::Incompatible(IncompatibleError::TooLong { length: 8, capacity: 6 })) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn written_characters_are_recognized() {
+        let font = font();
+        let dir = tempfile::tempdir().unwrap();
+        let mut image = blank_image(dir.path(), 20, 20);
+        let mut view = ImageView::new(&mut image, Vector2D::new(2, 2), Vector2D::new(14, 16));
+        font.put_string(&mut view, 1, 1, "AB CD").unwrap();
+        let read: Vec<Option<char>> = [(1, 1), (5, 1), (1, 8), (5, 8)].iter()
+            .map(|(x, y)| font.get_char(&view, *x, *y))
+            .collect();
+        assert_eq!(read, vec![Some('A'), Some('B'), Some('C'), Some('D')]);
+        assert_eq!(font.get_char(&view, 9, 1), None);
+    }
+
+    #[test]
+    fn lowercase_and_punctuation_are_read_in_5x7() {
+        let font = BitmapFont::open(concat!(env!("CARGO_MANIFEST_DIR"), "/../font2.png"), (5, 7),
+                                    r##"!"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\]^_`abcdefghijklmnopqrstuvwxyz{|}~"##);
+        let dir = tempfile::tempdir().unwrap();
+        let mut image = blank_image(dir.path(), 60, 20);
+        let mut view = ImageView::new(&mut image, Vector2D::new(0, 0), Vector2D::new(59, 19));
+        font.put_string(&mut view, 1, 1, "a,z!~").unwrap();
+        let read: Vec<Option<char>> = (0..5).map(|i| font.get_char(&view, 1 + i * font.char_step(), 1)).collect();
+        assert_eq!(read, vec![Some('a'), Some(','), Some('z'), Some('!'), Some('~')]);
+    }
+}
@@ -0,0 +1,112 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+// written after all entries, journal without it was interrupted and is not applied
+const COMMIT: u64 = u64::MAX;
+
+// Write-ahead journal of the bmp file: bytes to be written at some offsets of the file.
+// Entries are written and synced here before they are applied, so after a crash they are either
+// not applied at all or applied again from the journal.
+// Format: entries of (offset: u64, length: u32, bytes), then COMMIT, all little endian.
+pub(crate) struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    // "<file name>.journal" in the same directory
+    pub(crate) fn next_to(path: &Path) -> Self {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".journal");
+        Self { path: path.with_file_name(name) }
+    }
+
+    // Entries are on disk when it returns
+    pub(crate) fn write(&self, entries: &[(u64, &[u8])]) -> Result<(), Error> {
+        let file = OpenOptions::new().write(true).create(true).truncate(true).open(&self.path)?;
+        let mut writer = BufWriter::new(file);
+        for (offset, data) in entries {
+            writer.write_u64::<LittleEndian>(*offset)?;
+            writer.write_u32::<LittleEndian>(data.len() as u32)?;
+            writer.write_all(data)?;
+        }
+        let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+        // commit goes after entries are on disk, otherwise it could get there before them
+        file.sync_data()?;
+        file.write_u64::<LittleEndian>(COMMIT)?;
+        file.sync_data()
+    }
+
+    // Entries of the committed journal, empty when there is no journal or it was not committed
+    pub(crate) fn read(&self) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut reader = BufReader::new(file);
+        let mut entries = vec![];
+        loop {
+            let entry = reader.read_u64::<LittleEndian>().and_then(|offset| {
+                if offset == COMMIT {
+                    return Ok(None);
+                }
+                let mut data = vec![0; reader.read_u32::<LittleEndian>()? as usize];
+                reader.read_exact(&mut data)?;
+                Ok(Some((offset, data)))
+            });
+            match entry {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => return Ok(entries),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(vec![]),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Called when entries are applied and synced
+    pub(crate) fn clear(&self) -> Result<(), Error> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn committed_entries_are_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::next_to(&dir.path().join("db.bmp"));
+        journal.write(&[(54, &[1, 2, 3][..]), (1000, &[4][..])]).unwrap();
+        assert_eq!(journal.read().unwrap(), vec![(54, vec![1, 2, 3]), (1000, vec![4])]);
+        assert!(dir.path().join("db.bmp.journal").exists());
+    }
+
+    #[test]
+    fn journal_without_commit_is_not_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::next_to(&dir.path().join("db.bmp"));
+        journal.write(&[(54, &[1, 2, 3][..])]).unwrap();
+        let path = dir.path().join("db.bmp.journal");
+        let len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 8).unwrap();
+        assert!(journal.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn cleared_or_missing_journal_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = Journal::next_to(&dir.path().join("db.bmp"));
+        assert!(journal.read().unwrap().is_empty());
+        journal.clear().unwrap();
+        journal.write(&[(54, &[1][..])]).unwrap();
+        journal.clear().unwrap();
+        assert!(journal.read().unwrap().is_empty());
+    }
+}
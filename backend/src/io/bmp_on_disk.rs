@@ -1,15 +1,18 @@
 use std::cell::{RefCell, RefMut};
-use std::fs::File;
-use std::io::{Error, Read, Seek, SeekFrom, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Error, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::ops::Div;
 use log::info;
 use std::env;
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use image::{ColorType, RgbImage};
 use image::codecs::png::PngEncoder;
 
 use crate::image::{StorableImage, SyncResponse};
+use crate::io::bmp_journal::Journal;
+use crate::io::image_io::{temp_path, replace_with_temp};
 use crate::model::colors::RGB;
 use std::fmt::{Debug, Formatter};
 
@@ -60,8 +63,12 @@ impl BMPSlice {
         ((self.y_to_exclusive - self.y_from) * (self.bmp_params.width * 3 + self.bmp_params.data_padding)) as usize
     }
 
+    fn offset(&self) -> u64 {
+        (self.bmp_params.data_offset + self.y_from * (self.bmp_params.width * 3 + self.bmp_params.data_padding)) as u64
+    }
+
     fn seek(&self) -> SeekFrom {
-        SeekFrom::Start((self.bmp_params.data_offset + self.y_from * (self.bmp_params.width * 3 + self.bmp_params.data_padding)) as u64)
+        SeekFrom::Start(self.offset())
     }
 
     fn unload(&mut self) {
@@ -70,14 +77,19 @@ impl BMPSlice {
         info!("Unload slice {}-{}", self.y_from, self.y_to_exclusive)
     }
 
-    fn save_if_loaded_and_dirty(&mut self, file: &mut File) {
-        if self.data.is_some() && self.dirty {
-            file.seek(self.seek()).unwrap();
-            file.write(self.data.as_ref().unwrap().as_ref()).expect("Cannot write");
-            file.sync_all().expect("Cannot sync"); //todo: here?
+    // File is not synced, see `BMPOnDiskImage::save_dirty_slices`
+    fn save_if_loaded_and_dirty(&mut self, file: &mut File) -> Result<(), Error> {
+        if let Some(data) = self.data.as_ref().filter(|_| self.dirty) {
+            file.seek(self.seek())?;
+            file.write_all(data)?;
             self.dirty = false;
             info!("Saved slice {}-{}", self.y_from, self.y_to_exclusive)
         }
+        Ok(())
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.data.is_some() && self.dirty
     }
 
 
@@ -109,13 +121,17 @@ impl BMPSlice {
 
 pub struct BMPOnDiskImage {
     file: RefCell<File>,
+    path: PathBuf,
     bmp_params: BMPParams,
     slices: Vec<RefCell<BMPSlice>>,
-    next_loaded_nr: RefCell<u32>
+    next_loaded_nr: RefCell<u32>,
+    journal: Journal,
 }
 
 impl BMPOnDiskImage {
-    pub(crate) fn new(mut file: File) -> Self {
+    pub(crate) fn new(mut file: File, path: &Path, journal: Journal) -> Self {
+        Self::replay(&mut file, &journal).expect("Cannot replay journal");
+
         //read header, create bmp params
         file.seek(SeekFrom::Start(10)).unwrap();
         let data_offset = file.read_u32::<LittleEndian>().unwrap();
@@ -123,28 +139,72 @@ impl BMPOnDiskImage {
         file.seek(SeekFrom::Current(4)).unwrap();
         let width = file.read_u32::<LittleEndian>().unwrap();
         let height = file.read_u32::<LittleEndian>().unwrap();
-        let data_padding = (((width * 3) as f32).div(4.0).ceil() * 4.0) as u32 - (width * 3);
         let bmp_params = BMPParams {
             width,
             height,
             data_offset,
-            data_padding,
+            data_padding: Self::data_padding(width),
             slice_step: env::var("BMP_SLICE_STEP").unwrap_or("1024".to_string()).parse::<usize>().unwrap(),
             keep_in_memory_inv: env::var("BMP_KEEP_IN_MEM_INV").unwrap_or("2".to_string()).parse::<usize>().unwrap()
 
         };
-        let mut slices = vec![];
-        for y in (0..height).step_by(bmp_params.slice_step) {
-            slices.push(RefCell::new(BMPSlice::new(y, (y + bmp_params.slice_step as u32).min(height), bmp_params.clone())))
-        }
         Self {
             file: RefCell::new(file),
-            bmp_params: bmp_params.clone(),
-            slices,
-            next_loaded_nr: RefCell::new(1)
+            path: path.to_path_buf(),
+            slices: Self::create_slices(&bmp_params),
+            bmp_params,
+            next_loaded_nr: RefCell::new(1),
+            journal,
         }
     }
 
+    // Applies slices of the journal left by the interrupted sync. Not committed journal is dropped,
+    // the file wasn't touched by that sync yet.
+    fn replay(file: &mut File, journal: &Journal) -> Result<(), Error> {
+        let entries = journal.read()?;
+        for (offset, data) in &entries {
+            file.seek(SeekFrom::Start(*offset))?;
+            file.write_all(data)?;
+        }
+        if !entries.is_empty() {
+            file.sync_all()?;
+            info!("Replayed {} slices from the journal", entries.len());
+        }
+        journal.clear()
+    }
+
+    // Dirty slices go to the journal first, so the file has either old or new content of every slice after a crash
+    fn save_dirty_slices(&self, file: &mut File) -> Result<(), Error> {
+        let mut dirty: Vec<RefMut<BMPSlice>> = self.slices.iter()
+            .map(|slice| slice.borrow_mut())
+            .filter(|slice| slice.is_dirty())
+            .collect();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        let entries: Vec<(u64, &[u8])> = dirty.iter()
+            .map(|slice| (slice.offset(), slice.data.as_ref().unwrap().as_ref()))
+            .collect();
+        self.journal.write(&entries)?;
+        for slice in dirty.iter_mut() {
+            slice.save_if_loaded_and_dirty(file)?;
+        }
+        file.sync_all()?;
+        self.journal.clear()
+    }
+
+    fn data_padding(width: u32) -> u32 {
+        (((width * 3) as f32).div(4.0).ceil() * 4.0) as u32 - (width * 3)
+    }
+
+    fn create_slices(bmp_params: &BMPParams) -> Vec<RefCell<BMPSlice>> {
+        let mut slices = vec![];
+        for y in (0..bmp_params.height).step_by(bmp_params.slice_step) {
+            slices.push(RefCell::new(BMPSlice::new(y, (y + bmp_params.slice_step as u32).min(bmp_params.height), bmp_params.clone())))
+        }
+        slices
+    }
+
     fn load_slice_if_needed(&self, slice: &mut RefMut<BMPSlice>) {
         if !slice.is_loaded() {
             slice.load(&mut self.file.borrow_mut());
@@ -179,14 +239,67 @@ impl StorableImage for BMPOnDiskImage {
         self.bmp_params.height
     }
 
+    // Rows are not moved in place: resized image is written to a temporary file which then replaces the file
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), Error> {
+        assert!(width >= self.bmp_params.width && height >= self.bmp_params.height);
+        let mut file = self.file.borrow_mut();
+        self.save_dirty_slices(&mut file)?;
+        for slice in &self.slices {
+            let mut slice = slice.borrow_mut();
+            if slice.is_loaded() {
+                slice.unload();
+            }
+        }
+        let old_params = self.bmp_params.clone();
+        let data_offset = old_params.data_offset as u64;
+        let old_row_size = (old_params.width * 3 + old_params.data_padding) as usize;
+        let row_size = (width * 3 + Self::data_padding(width)) as usize;
+        let image_size = row_size as u32 * height;
+        let mut header = vec![0; data_offset as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        (&mut header[2..]).write_u32::<LittleEndian>(old_params.data_offset + image_size)?;
+        (&mut header[18..]).write_u32::<LittleEndian>(width)?;
+        (&mut header[22..]).write_u32::<LittleEndian>(height)?;
+        (&mut header[34..]).write_u32::<LittleEndian>(image_size)?;
+
+        let mut writer = BufWriter::new(File::create(temp_path(&self.path))?);
+        writer.write_all(&header)?;
+        // rows are stored bottom-up, so new rows at the bottom go before the existing ones
+        let mut blank_row = vec![255; (width * 3) as usize];
+        blank_row.resize(row_size, 0);
+        for _ in old_params.height..height {
+            writer.write_all(&blank_row)?;
+        }
+        let mut reader = BufReader::new(&*file);
+        let mut row = vec![0; old_row_size];
+        for _ in 0..old_params.height {
+            reader.read_exact(row.as_mut_slice())?;
+            let mut new_row = row[..(old_params.width * 3) as usize].to_vec();
+            new_row.resize((width * 3) as usize, 255);
+            new_row.resize(row_size, 0);
+            writer.write_all(&new_row)?;
+        }
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        replace_with_temp(&self.path)?;
+        *file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        info!("Resized {}x{} -> {}x{}", old_params.width, old_params.height, width, height);
+
+        self.bmp_params.width = width;
+        self.bmp_params.height = height;
+        self.bmp_params.data_padding = Self::data_padding(width);
+        self.slices = Self::create_slices(&self.bmp_params);
+        Ok(())
+    }
+
     fn sync(&mut self) -> Result<SyncResponse, Error> {
         //todo: last modified - also check
         let mut file_ref = self.file.borrow_mut();
+        self.save_dirty_slices(&mut file_ref)?;
         let mut loaded = 0;
         let count = self.slices.len();
         for slice in &self.slices {
-            let mut slice = slice.borrow_mut();
-            slice.save_if_loaded_and_dirty(&mut file_ref);
+            let slice = slice.borrow();
             if slice.is_loaded() {
                 loaded += 1;
             }
@@ -201,6 +314,10 @@ impl StorableImage for BMPOnDiskImage {
         Ok(SyncResponse::Ok)
     }
 
+    fn flush(&mut self) -> Result<(), Error> {
+        self.sync().map(|_| ())
+    }
+
     fn optimize(&self) {
         let mut loaded = 0;
         let count = self.slices.len();
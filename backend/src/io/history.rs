@@ -0,0 +1,297 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use image::{ColorType, RgbImage};
+use image::codecs::png::PngEncoder;
+
+use crate::model::colors::RGB;
+use crate::model::model::Vector2D;
+
+// One write of the field: pixels of its data area before and after it
+#[derive(Debug, Clone)]
+pub struct FieldChange {
+    // number of the change in the history of the whole image, starting from 0
+    pub version: u64,
+    pub record: Vector2D,
+    pub field: u32,
+    // milliseconds since the epoch
+    pub time: u64,
+    pub client: String,
+    // data area of the field, pixels are row by row
+    pub data_start: Vector2D,
+    pub data_end: Vector2D,
+    pub old_pixels: Vec<RGB>,
+    pub new_pixels: Vec<RGB>,
+}
+
+impl FieldChange {
+    pub fn width(&self) -> u32 {
+        self.data_end.x - self.data_start.x + 1
+    }
+
+    pub fn height(&self) -> u32 {
+        self.data_end.y - self.data_start.y + 1
+    }
+
+    // png, like image fields
+    pub fn old_base64(&self) -> String {
+        pixels_base64(self.width(), self.height(), &self.old_pixels)
+    }
+
+    pub fn new_base64(&self) -> String {
+        pixels_base64(self.width(), self.height(), &self.new_pixels)
+    }
+}
+
+fn pixels_base64(width: u32, height: u32, pixels: &[RGB]) -> String {
+    let mut temp_image: RgbImage = image::ImageBuffer::new(width, height);
+    for (idx, pixel) in pixels.iter().enumerate() {
+        let idx = idx as u32;
+        temp_image.put_pixel(idx % width, idx / width, (*pixel).into());
+    }
+    let mut buf: Vec<u8> = vec![];
+    PngEncoder::new(&mut buf)
+        .encode(temp_image.as_raw(), width, height, ColorType::Rgb8).unwrap();
+    base64::encode(&buf)
+}
+
+// Append-only log of field writes, nothing is ever removed from it.
+// Format: entries of (length of the rest: u32, record x, y: u32, field: u32, time: u64,
+// client length: u16, client, data start x, y: u32, data end x, y: u32, old pixels, new pixels),
+// pixels are r, g, b bytes, all little endian. Entry of only record x, y marks deletion of the record,
+// changes before it belong to the deleted record and not to one created later at the same place.
+// The file is scanned once to index entries by record, after that a record history is read without the scan.
+// Each append is synced to disk, that is one sync per write request.
+pub(crate) struct History {
+    path: PathBuf,
+    // built on first use, dropped after a failed append
+    index: Option<Index>,
+}
+
+#[derive(Default)]
+struct Index {
+    // number of entries and length of the file
+    len: u64,
+    end: u64,
+    // (version, offset) of changes of the record since its deletion
+    entries: HashMap<Vector2D, Vec<(u64, u64)>>,
+}
+
+impl Index {
+    fn add(&mut self, record: Vector2D, entry_len: u32) {
+        if entry_len == 8 {
+            self.entries.remove(&record);
+        } else {
+            self.entries.entry(record).or_default().push((self.len, self.end));
+        }
+        self.len += 1;
+        self.end += 4 + entry_len as u64;
+    }
+}
+
+impl History {
+    // "<file name>.history" in the same directory
+    pub(crate) fn next_to(path: &Path) -> Self {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".history");
+        Self { path: path.with_file_name(name), index: None }
+    }
+
+    // Changes are on disk when it returns, versions are assigned in the order of changes, theirs are ignored
+    pub(crate) fn append(&mut self, changes: &[FieldChange]) -> Result<(), Error> {
+        let mut entries = vec![];
+        for change in changes {
+            let mut entry = vec![];
+            entry.write_u32::<LittleEndian>(change.field)?;
+            entry.write_u64::<LittleEndian>(change.time)?;
+            let client = &change.client.as_bytes()[..change.client.len().min(u16::MAX as usize)];
+            entry.write_u16::<LittleEndian>(client.len() as u16)?;
+            entry.write_all(client)?;
+            for point in [change.data_start, change.data_end].iter() {
+                entry.write_u32::<LittleEndian>(point.x)?;
+                entry.write_u32::<LittleEndian>(point.y)?;
+            }
+            for pixel in change.old_pixels.iter().chain(change.new_pixels.iter()) {
+                entry.write_all(&[pixel.r, pixel.g, pixel.b])?;
+            }
+            entries.push((change.record, entry));
+        }
+        self.write(&entries)
+    }
+
+    // History of the record ends here
+    pub(crate) fn append_deletion(&mut self, record: Vector2D) -> Result<(), Error> {
+        self.write(&[(record, vec![])])
+    }
+
+    // Changes of the record fields since it was created, oldest first
+    pub(crate) fn read(&mut self, record: Vector2D) -> Result<Vec<FieldChange>, Error> {
+        let offsets = match self.index()?.entries.get(&record) {
+            Some(offsets) => offsets.clone(),
+            None => return Ok(vec![]),
+        };
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        offsets.into_iter()
+            .map(|(version, offset)| {
+                reader.seek(SeekFrom::Start(offset))?;
+                read_entry(&mut reader, version)
+            })
+            .collect()
+    }
+
+    fn index(&mut self) -> Result<&Index, Error> {
+        if self.index.is_none() {
+            self.index = Some(match OpenOptions::new().read(true).write(true).open(&self.path) {
+                Ok(mut file) => build_index(&mut file)?,
+                Err(e) if e.kind() == ErrorKind::NotFound => Index::default(),
+                Err(e) => return Err(e),
+            });
+        }
+        Ok(self.index.as_ref().unwrap())
+    }
+
+    fn write(&mut self, entries: &[(Vector2D, Vec<u8>)]) -> Result<(), Error> {
+        self.index()?;
+        // built again after a failed write, which could leave a part of the entries
+        let mut index = self.index.take().unwrap();
+        let mut bytes = vec![];
+        for (record, entry) in entries {
+            let entry_len = 8 + entry.len() as u32;
+            bytes.write_u32::<LittleEndian>(entry_len)?;
+            bytes.write_u32::<LittleEndian>(record.x)?;
+            bytes.write_u32::<LittleEndian>(record.y)?;
+            bytes.write_all(entry)?;
+            index.add(*record, entry_len);
+        }
+        let mut file = OpenOptions::new().append(true).create(true).open(&self.path)?;
+        file.write_all(&bytes)?;
+        file.sync_data()?;
+        self.index = Some(index);
+        Ok(())
+    }
+}
+
+// Cuts off the incomplete entry at the end, if any (the process was killed during append),
+// so new entries are appended after complete ones
+fn build_index(file: &mut File) -> Result<Index, Error> {
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(&*file);
+    let mut index = Index::default();
+    while index.end + 12 <= file_len {
+        let entry_len = reader.read_u32::<LittleEndian>()?;
+        if entry_len < 8 || index.end + 4 + entry_len as u64 > file_len {
+            break;
+        }
+        let record = Vector2D::new(reader.read_u32::<LittleEndian>()?, reader.read_u32::<LittleEndian>()?);
+        reader.seek_relative(entry_len as i64 - 8)?;
+        index.add(record, entry_len);
+    }
+    if index.end < file_len {
+        file.set_len(index.end)?;
+    }
+    Ok(index)
+}
+
+fn read_entry(reader: &mut BufReader<File>, version: u64) -> Result<FieldChange, Error> {
+    let len = reader.read_u32::<LittleEndian>()?;
+    let record = Vector2D::new(reader.read_u32::<LittleEndian>()?, reader.read_u32::<LittleEndian>()?);
+    let mut entry = vec![0; (len as usize).saturating_sub(8)];
+    reader.read_exact(&mut entry)?;
+    let mut entry = entry.as_slice();
+    let field = entry.read_u32::<LittleEndian>()?;
+    let time = entry.read_u64::<LittleEndian>()?;
+    let mut client = vec![0; entry.read_u16::<LittleEndian>()? as usize];
+    entry.read_exact(&mut client)?;
+    let data_start = Vector2D::new(entry.read_u32::<LittleEndian>()?, entry.read_u32::<LittleEndian>()?);
+    let data_end = Vector2D::new(entry.read_u32::<LittleEndian>()?, entry.read_u32::<LittleEndian>()?);
+    let pixels: Vec<RGB> = entry.chunks_exact(3).map(|c| RGB { r: c[0], g: c[1], b: c[2] }).collect();
+    let (old_pixels, new_pixels) = pixels.split_at(pixels.len() / 2);
+    Ok(FieldChange {
+        version,
+        record,
+        field,
+        time,
+        client: String::from_utf8_lossy(&client).to_string(),
+        data_start,
+        data_end,
+        old_pixels: old_pixels.to_vec(),
+        new_pixels: new_pixels.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(record: Vector2D, field: u32, old: u8, new: u8) -> FieldChange {
+        FieldChange {
+            version: 0,
+            record,
+            field,
+            time: 1_600_000_000_000,
+            client: "test".to_string(),
+            data_start: Vector2D::new(10, 20),
+            data_end: Vector2D::new(11, 20),
+            old_pixels: vec![RGB { r: old, g: old, b: old }; 2],
+            new_pixels: vec![RGB { r: new, g: new, b: new }; 2],
+        }
+    }
+
+    fn summary(changes: &[FieldChange]) -> Vec<(u64, u32, u8, u8)> {
+        changes.iter().map(|c| (c.version, c.field, c.old_pixels[0].r, c.new_pixels[0].r)).collect()
+    }
+
+    #[test]
+    fn changes_are_read_by_record_with_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (Vector2D::new(1, 2), Vector2D::new(3, 4));
+        let mut history = History::next_to(&dir.path().join("db.png"));
+        assert!(history.read(a).unwrap().is_empty());
+        history.append(&[change(a, 0, 255, 0), change(b, 1, 255, 1)]).unwrap();
+        history.append(&[change(a, 2, 0, 2)]).unwrap();
+
+        assert_eq!(summary(&history.read(a).unwrap()), vec![(0, 0, 255, 0), (2, 2, 0, 2)]);
+        assert_eq!(summary(&history.read(b).unwrap()), vec![(1, 1, 255, 1)]);
+        let read = history.read(a).unwrap();
+        assert_eq!((read[0].client.as_str(), read[0].data_end, read[0].width(), read[0].height()), ("test", Vector2D::new(11, 20), 2, 1));
+
+        // index is built from the file when it is opened again
+        let mut reopened = History::next_to(&dir.path().join("db.png"));
+        assert_eq!(summary(&reopened.read(a).unwrap()), vec![(0, 0, 255, 0), (2, 2, 0, 2)]);
+    }
+
+    #[test]
+    fn deletion_ends_history_of_the_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (Vector2D::new(1, 2), Vector2D::new(3, 4));
+        let mut history = History::next_to(&dir.path().join("db.png"));
+        history.append(&[change(a, 0, 255, 0), change(b, 0, 255, 0)]).unwrap();
+        history.append_deletion(a).unwrap();
+        assert!(history.read(a).unwrap().is_empty());
+
+        // new record at the same place
+        history.append(&[change(a, 1, 255, 1)]).unwrap();
+        assert_eq!(summary(&history.read(a).unwrap()), vec![(3, 1, 255, 1)]);
+        assert_eq!(summary(&history.read(b).unwrap()), vec![(1, 0, 255, 0)]);
+        let mut reopened = History::next_to(&dir.path().join("db.png"));
+        assert_eq!(summary(&reopened.read(a).unwrap()), vec![(3, 1, 255, 1)]);
+    }
+
+    #[test]
+    fn incomplete_entry_is_cut_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = Vector2D::new(1, 2);
+        let mut history = History::next_to(&dir.path().join("db.png"));
+        history.append(&[change(a, 0, 255, 0)]).unwrap();
+        // the process was killed in the middle of the next append
+        let mut file = OpenOptions::new().append(true).open(dir.path().join("db.png.history")).unwrap();
+        file.write_all(&[100, 0, 0, 0, 1, 0]).unwrap();
+
+        let mut reopened = History::next_to(&dir.path().join("db.png"));
+        reopened.append(&[change(a, 1, 0, 1)]).unwrap();
+        assert_eq!(summary(&reopened.read(a).unwrap()), vec![(0, 0, 255, 0), (1, 1, 0, 1)]);
+    }
+}
@@ -1,7 +1,9 @@
-use std::fs::{OpenOptions};
-use std::path::{Path};
+use std::fs::{File, OpenOptions};
+use std::io::Error;
+use std::path::{Path, PathBuf};
 use crate::image::{BoxedStorableImage};
 use crate::io::bmp_on_disk::BMPOnDiskImage;
+use crate::io::bmp_journal::Journal;
 use crate::io::in_memory_image::InMemoryImage;
 
 pub fn load_image(path: &str) -> BoxedStorableImage {
@@ -9,7 +11,34 @@ pub fn load_image(path: &str) -> BoxedStorableImage {
     let file = OpenOptions::new().read(true).write(true).open(path).unwrap();
     let extension = path.extension().unwrap().to_str().unwrap();
     match extension {
-        "bmp" => Box::new(BMPOnDiskImage::new(file)),
+        "bmp" => Box::new(BMPOnDiskImage::new(file, path, Journal::next_to(path))),
         _ => Box::new(InMemoryImage::new(&path))
     }
 }
+
+// "<file name>.tmp" in the same directory, new content of the file is written there and then renamed over it
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+// The written and synced temporary file takes place of `path`, so the file has either old or new content
+// whenever the process is killed
+pub(crate) fn replace_with_temp(path: &Path) -> Result<(), Error> {
+    std::fs::rename(temp_path(path), path)?;
+    // rename is durable when the directory is synced, not every platform can open a directory for that
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+// White png of the size in `dir`, loaded as the database image would be
+#[cfg(test)]
+pub(crate) fn blank_image(dir: &Path, width: u32, height: u32) -> BoxedStorableImage {
+    let path = dir.join("db.png");
+    image::RgbImage::from_pixel(width, height, image::Rgb([255, 255, 255])).save(&path).unwrap();
+    load_image(path.to_str().unwrap())
+}
@@ -1,19 +1,26 @@
-use std::io::{Error, ErrorKind};
+use std::fs::File;
+use std::io::{BufWriter, Error};
 use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
 use std::time::SystemTime;
 
-use image::{ColorType, DynamicImage, GenericImage, GenericImageView, ImageResult};
+use image::{ColorType, DynamicImage, GenericImage, GenericImageView, ImageFormat, Rgb, Rgba, RgbImage, RgbaImage};
 use image::codecs::png::PngEncoder;
 
 use crate::image::{StorableImage, SyncResponse};
 use crate::model::colors::RGB;
+use crate::model::blocks_map::TILE_SIZE;
+use crate::io::image_io::{temp_path, replace_with_temp};
 use std::fmt::{Debug, Formatter};
 
 pub struct InMemoryImage {
     image: DynamicImage,
     path: PathBuf,
     dirty: bool,
+    // of the file when it was read or written by us
     last_modified_time: SystemTime,
+    // save in progress, gives modification time of the written file
+    saving: Option<JoinHandle<Result<SystemTime, Error>>>,
 }
 
 impl InMemoryImage {
@@ -23,8 +30,34 @@ impl InMemoryImage {
             path: path.to_path_buf(),
             dirty: false,
             last_modified_time: std::fs::metadata(path).unwrap().modified().unwrap(),
+            saving: None,
         }
     }
+
+    // Waits for the save in progress. Its modification time is remembered, so the file written by us
+    // is not taken for a change made outside. Failed save is repeated on the next sync.
+    fn finish_saving(&mut self) {
+        if let Some(saving) = self.saving.take() {
+            match saving.join().unwrap() {
+                Ok(modified) => self.last_modified_time = self.last_modified_time.max(modified),
+                Err(e) => {
+                    log::error!("Error during saving {}", e);
+                    self.dirty = true;
+                }
+            }
+        }
+    }
+}
+
+// Writes a temporary file next to `path` and renames it over `path`, so the file has either old or new image
+// whenever the process is killed. Returns modification time of the written file.
+fn save_atomically(image: &DynamicImage, path: &Path) -> Result<SystemTime, Error> {
+    let format = ImageFormat::from_path(path).map_err(|e| Error::other(e.to_string()))?;
+    let mut writer = BufWriter::new(File::create(temp_path(path))?);
+    image.write_to(&mut writer, format).map_err(|e| Error::other(e.to_string()))?;
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    replace_with_temp(path)?;
+    std::fs::metadata(path)?.modified()
 }
 
 impl StorableImage for InMemoryImage {
@@ -46,28 +79,59 @@ impl StorableImage for InMemoryImage {
         self.image.height()
     }
 
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), Error> {
+        let mut resized = match self.image {
+            DynamicImage::ImageRgb8(_) => DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, Rgb([255, 255, 255]))),
+            _ => DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, Rgba([255, 255, 255, 255])))
+        };
+        resized.copy_from(&self.image, 0, 0).map_err(|e| Error::other(e.to_string()))?;
+        self.image = resized;
+        self.dirty = true;
+        Ok(())
+    }
+
     fn sync(&mut self) -> Result<crate::image::SyncResponse, Error> {
+        if self.saving.as_ref().is_some_and(|saving| saving.is_finished()) {
+            self.finish_saving();
+        }
         let path = self.path.as_path();
         let modified = std::fs::metadata(path)?.modified().unwrap();
-        if self.dirty {
+        if self.saving.is_some() {
+            // the file is being written by us
+            Ok(SyncResponse::Ok)
+        } else if self.dirty {
             let copied = self.image.clone();
             let copied_path = path.to_path_buf();
-            tokio::spawn( async move {
-                if let ImageResult::Err(e) = copied.save(copied_path) {
-                    log::error!("Error during saving {}", e)
-                }
-            });
+            self.saving = Some(std::thread::spawn(move || save_atomically(&copied, &copied_path)));
             self.dirty = false;
             Ok(SyncResponse::Ok)
         } else if modified > self.last_modified_time {
             self.last_modified_time = modified;
-            self.image = image::open(path).map_err(|_| Error::new(ErrorKind::Other, "Cannot load"))?;
-            Ok(SyncResponse::Reloaded)
+            let image = image::open(path).map_err(|_| Error::other("Cannot load"))?;
+            let response = if image.dimensions() == self.image.dimensions() {
+                match changed_tiles(&self.image, &image) {
+                    tiles if tiles.is_empty() => SyncResponse::Ok,
+                    tiles => SyncResponse::Changed(tiles),
+                }
+            } else {
+                SyncResponse::Reloaded
+            };
+            self.image = image;
+            Ok(response)
         } else {
             Ok(SyncResponse::Ok)
         }
     }
 
+    fn flush(&mut self) -> Result<(), Error> {
+        self.finish_saving();
+        if self.dirty {
+            self.last_modified_time = save_atomically(&self.image, &self.path)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
     fn optimize(&self) {
 
     }
@@ -81,6 +145,22 @@ impl StorableImage for InMemoryImage {
     }
 }
 
+// Tiles (column, row) with different pixels in images of the same size
+fn changed_tiles(old: &DynamicImage, new: &DynamicImage) -> Vec<(u32, u32)> {
+    let (width, height) = old.dimensions();
+    let mut tiles = vec![];
+    for ty in 0..height.div_ceil(TILE_SIZE) {
+        for tx in 0..width.div_ceil(TILE_SIZE) {
+            let xs = tx * TILE_SIZE..width.min((tx + 1) * TILE_SIZE);
+            let differs = (ty * TILE_SIZE..height.min((ty + 1) * TILE_SIZE))
+                .any(|y| xs.clone().any(|x| RGB::from(old.get_pixel(x, y)) != RGB::from(new.get_pixel(x, y))));
+            if differs {
+                tiles.push((tx, ty));
+            }
+        }
+    }
+    tiles
+}
 
 impl Debug for InMemoryImage {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -1,6 +1,9 @@
 pub mod bitmap_font;
 pub mod image_io;
+pub mod snapshots;
+pub mod history;
 mod in_memory_image;
 mod bmp_on_disk;
+mod bmp_journal;
 
 
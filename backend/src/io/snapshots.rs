@@ -0,0 +1,124 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::io::image_io::{replace_with_temp, temp_path};
+
+// Copy of the image file, id is the creation time in milliseconds since the epoch
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub id: u64,
+    pub size: u64,
+}
+
+// Snapshots of "db/db.png" are "db/db.png.snapshots/<id>.png"
+fn snapshots_dir(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".snapshots");
+    path.with_file_name(name)
+}
+
+fn snapshot_path(path: &Path, id: u64) -> PathBuf {
+    let mut name = id.to_string();
+    if let Some(extension) = path.extension() {
+        name.push('.');
+        name.push_str(&extension.to_string_lossy());
+    }
+    snapshots_dir(path).join(name)
+}
+
+// Copies `from` to a temporary file next to `to` and renames it, so `to` is never partially written
+fn copy_atomically(from: &Path, to: &Path) -> Result<(), Error> {
+    std::fs::copy(from, temp_path(to))?;
+    File::open(temp_path(to))?.sync_all()?;
+    replace_with_temp(to)
+}
+
+// Copies the image file, it should be flushed before
+pub(crate) fn create_snapshot(path: &Path) -> Result<Snapshot, Error> {
+    std::fs::create_dir_all(snapshots_dir(path))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    // two snapshots within a millisecond get different ids
+    let id = list_snapshots(path)?.last().map_or(now, |last| now.max(last.id + 1));
+    copy_atomically(path, &snapshot_path(path, id))?;
+    Ok(Snapshot { id, size: std::fs::metadata(snapshot_path(path, id))?.len() })
+}
+
+// Oldest first
+pub(crate) fn list_snapshots(path: &Path) -> Result<Vec<Snapshot>, Error> {
+    let entries = match std::fs::read_dir(snapshots_dir(path)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut snapshots = vec![];
+    for entry in entries {
+        let entry = entry?;
+        // temporary files of unfinished copies have no numeric stem
+        let id = entry.path().file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse().ok());
+        if let Some(id) = id {
+            snapshots.push(Snapshot { id, size: entry.metadata()?.len() });
+        }
+    }
+    snapshots.sort_by_key(|snapshot| snapshot.id);
+    Ok(snapshots)
+}
+
+// Puts the snapshot in place of the image file, the image has to be opened again after that
+pub(crate) fn restore_snapshot(path: &Path, id: u64) -> Result<(), Error> {
+    let snapshot = snapshot_path(path, id);
+    if !snapshot.exists() {
+        return Err(Error::new(ErrorKind::NotFound, format!("Snapshot {} not found", id)));
+    }
+    copy_atomically(&snapshot, path)
+}
+
+// Removes all but `keep` newest snapshots, returns removed ones
+pub(crate) fn prune_snapshots(path: &Path, keep: usize) -> Result<Vec<Snapshot>, Error> {
+    let snapshots = list_snapshots(path)?;
+    let removed = snapshots[..snapshots.len().saturating_sub(keep)].to_vec();
+    for snapshot in &removed {
+        std::fs::remove_file(snapshot_path(path, snapshot.id))?;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_are_created_listed_and_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.png");
+        std::fs::write(&path, b"first").unwrap();
+        let first = create_snapshot(&path).unwrap();
+        std::fs::write(&path, b"second!").unwrap();
+        let second = create_snapshot(&path).unwrap();
+        assert!(second.id > first.id);
+        assert!(dir.path().join("db.png.snapshots").join(format!("{}.png", second.id)).exists());
+
+        let listed: Vec<(u64, u64)> = list_snapshots(&path).unwrap().iter().map(|s| (s.id, s.size)).collect();
+        assert_eq!(listed, vec![(first.id, 5), (second.id, 7)]);
+
+        let removed: Vec<u64> = prune_snapshots(&path, 1).unwrap().iter().map(|s| s.id).collect();
+        assert_eq!(removed, vec![first.id]);
+        let left: Vec<u64> = list_snapshots(&path).unwrap().iter().map(|s| s.id).collect();
+        assert_eq!(left, vec![second.id]);
+    }
+
+    #[test]
+    fn snapshot_is_restored_over_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.png");
+        assert!(list_snapshots(&path).unwrap().is_empty());
+        std::fs::write(&path, b"before").unwrap();
+        let snapshot = create_snapshot(&path).unwrap();
+        std::fs::write(&path, b"after").unwrap();
+
+        restore_snapshot(&path, snapshot.id).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"before");
+        assert_eq!(restore_snapshot(&path, snapshot.id + 1).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+}
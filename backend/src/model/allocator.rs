@@ -0,0 +1,142 @@
+use crate::model::model::{Record, Vector2D, DataError};
+use crate::model::blocks_map::{BlocksMap, Block};
+use crate::model::colors::RGB;
+use crate::model::layout::GLYPH_SIZE;
+use crate::image::ImageView;
+use std::collections::HashSet;
+
+// free space kept around records and other drawings
+pub(crate) const MARGIN: u32 = 10;
+// rows 0..3 keep column markers and default type glyph
+const RESERVED_ROWS: u32 = 3;
+const FIRST_X: u32 = 4;
+// step for columns which are not near any record
+const COLUMN_STEP: usize = 32;
+
+// Occupancy index over record areas (type glyphs included)
+pub(crate) struct FreeSpace {
+    occupied: BlocksMap,
+}
+
+impl FreeSpace {
+    pub(crate) fn new(records: &[Record]) -> Self {
+        let mut occupied = BlocksMap::new();
+        for (block_id, rec) in records.iter().enumerate() {
+            occupied.add(Block {
+                block_id,
+                x1: rec.position.x,
+                y1: rec.position.y.saturating_sub(GLYPH_SIZE),
+                x2: rec.rb_position.x,
+                y2: rec.rb_position.y,
+            });
+        }
+        Self { occupied }
+    }
+
+    // Finds top-left corner for a rectangle of `size` which keeps MARGIN from records and any other non-blank pixels.
+    // Pixel (x, 0) shall be blank or have `column` color, so the rectangle could be marked with it.
+    // Tries `preferred_x` first, then columns already marked with `column`, then the rest of the image from left to right.
+    pub(crate) fn allocate(&self, image: &ImageView, size: Vector2D, column: &RGB, preferred_x: Option<u32>) -> Result<Vector2D, DataError> {
+        let mut candidates: Vec<u32> = preferred_x.into_iter().collect();
+        candidates.extend((FIRST_X..image.width).filter(|x| image.get_pixel(*x, 0) == *column));
+        let mut free_columns: Vec<u32> = self.occupied.get_blocks().iter().map(|b| b.x2 + MARGIN + 1).collect();
+        free_columns.extend((FIRST_X..image.width).step_by(COLUMN_STEP));
+        free_columns.sort_unstable();
+        candidates.append(&mut free_columns);
+
+        let mut checked = HashSet::new();
+        for x in candidates {
+            if !checked.insert(x) { continue; }
+            if let Some(y) = self.find_in_column(image, x, size, column) {
+                return Ok(Vector2D::new(x, y));
+            }
+        }
+        Err(DataError::NoSpace)
+    }
+
+    fn find_in_column(&self, image: &ImageView, x: u32, size: Vector2D, column: &RGB) -> Option<u32> {
+        if x < FIRST_X || x + size.x > image.width {
+            return None;
+        }
+        let marker = image.get_pixel(x, 0);
+        if marker != *column && !marker.is_blank() {
+            return None;
+        }
+        let x1 = x.saturating_sub(MARGIN);
+        let x2 = (x + size.x - 1 + MARGIN).min(image.width - 1);
+        let mut y = RESERVED_ROWS + 1;
+        while y + size.y <= image.height {
+            let y1 = y.saturating_sub(MARGIN).max(RESERVED_ROWS);
+            let y2 = (y + size.y - 1 + MARGIN).min(image.height - 1);
+            if let Some(block) = self.occupied.get_lowest_intersecting(x1, y1, x2, y2) {
+                y = block.y2 + MARGIN + 1;
+                continue;
+            }
+            match (y1..=y2).rev().find(|yy| (x1..=x2).any(|xx| !image.get_pixel(xx, *yy).is_blank())) {
+                Some(non_blank_y) => y = non_blank_y + MARGIN + 1,
+                None => return Some(y)
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::image_io::blank_image;
+    use crate::model::datatypes::boolean::BOOL_TYPE;
+    use crate::model::layout::{layout_record, record_size};
+
+    const RED: RGB = RGB { r: 0xED, g: 0x1C, b: 0x24 };
+    const BLUE: RGB = RGB { r: 0, g: 0, b: 0xFF };
+
+    fn size() -> Vector2D {
+        record_size(&[Vector2D::new(20, 10)])
+    }
+
+    #[test]
+    fn first_place_is_below_reserved_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut image = blank_image(dir.path(), 100, 100);
+        let place = FreeSpace::new(&[]).allocate(&ImageView::from(&mut image), size(), &RED, None).unwrap();
+        assert_eq!(place, Vector2D::new(FIRST_X, RESERVED_ROWS + 1));
+    }
+
+    #[test]
+    fn margin_is_kept_below_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut image = blank_image(dir.path(), 100, 100);
+        let rec = layout_record(Vector2D::new(FIRST_X, RESERVED_ROWS + 1 + GLYPH_SIZE), &RED, &[BOOL_TYPE], &[Vector2D::new(20, 10)]).unwrap();
+        let place = FreeSpace::new(std::slice::from_ref(&rec)).allocate(&ImageView::from(&mut image), size(), &RED, Some(FIRST_X)).unwrap();
+        assert_eq!(place, Vector2D::new(FIRST_X, rec.rb_position.y + MARGIN + 1));
+    }
+
+    #[test]
+    fn margin_is_kept_below_other_drawings() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut image = blank_image(dir.path(), 100, 100);
+        let mut view = ImageView::from(&mut image);
+        view.set_pixel(10, 20, RGB::new(0, 0, 0)).unwrap();
+        let place = FreeSpace::new(&[]).allocate(&view, size(), &RED, None).unwrap();
+        assert_eq!(place, Vector2D::new(FIRST_X, 20 + MARGIN + 1));
+    }
+
+    #[test]
+    fn columns_of_other_color_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut image = blank_image(dir.path(), 100, 100);
+        let mut view = ImageView::from(&mut image);
+        view.set_pixel(FIRST_X, 0, BLUE).unwrap();
+        let place = FreeSpace::new(&[]).allocate(&view, size(), &RED, Some(FIRST_X)).unwrap();
+        assert_eq!(place, Vector2D::new(FIRST_X + COLUMN_STEP as u32, RESERVED_ROWS + 1));
+    }
+
+    #[test]
+    fn no_space_for_too_big_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut image = blank_image(dir.path(), 20, 20);
+        let result = FreeSpace::new(&[]).allocate(&ImageView::from(&mut image), size(), &RED, None);
+        assert!(matches!(result, Err(DataError::NoSpace)));
+    }
+}
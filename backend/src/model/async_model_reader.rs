@@ -2,7 +2,6 @@ use crate::model::model::{Model, Vector2D, Field, FieldType, Record};
 use std::collections::{HashMap, HashSet};
 use crate::model::datatypes::reference::REFERENCE_TYPE;
 use crate::image::ImageView;
-use std::collections::hash_map::Entry;
 use crate::model::datatypes::DEFAULT_TYPE;
 use tokio::sync::mpsc::{UnboundedSender};
 use crate::io::image_io::load_image;
@@ -10,68 +9,9 @@ use crate::db::DBMessage;
 use std::sync::{Arc, Mutex};
 use log::info;
 use std::time::SystemTime;
-
-#[derive(Clone, Copy, Debug)]
-struct Block {
-    x1: u32,
-    y1: u32,
-    x2: u32,
-    y2: u32,
-    block_id: usize,
-}
-
-impl Block {
-    fn contains(&self, x: u32, y: u32) -> bool {
-        x >= self.x1 && y >= self.y1 && x <= self.x2 && y <= self.y2
-    }
-}
-
-// 1024x1024 pixels blocks
-struct BlocksMap {
-    map: HashMap<(u32, u32), Vec<Block>>,
-}
-
-impl BlocksMap {
-    fn new() -> Self {
-        Self { map: HashMap::new() }
-    }
-
-    fn add(&mut self, block: Block) {
-        let x1 = block.x1 / 1024;
-        let x2 = block.x2 / 1024;
-        let y1 = block.y1 / 1024;
-        let y2 = block.y2 / 1024;
-        for x in x1..=x2 {
-            for y in y1..=y2 {
-                match self.map.entry((x, y)) {
-                    Entry::Occupied(mut vector) => {
-                        vector.get_mut().push(block.clone());
-                    }
-                    Entry::Vacant(entry) => {
-                        entry.insert(vec![block.clone()]);
-                    }
-                };
-            }
-        }
-    }
-
-    fn get_block(&self, x: u32, y: u32) -> Option<&Block> {
-        match self.map.get(&(x / 1024, y / 1024)) {
-            Some(vec) => vec.iter().find(|b| b.contains(x, y)),
-            None => None
-        }
-    }
-
-    fn get_blocks(&self) -> Vec<&Block> {
-        let mut v: Vec<&Block> = self.map.values()
-            .flatten()
-            .collect();
-
-        v.sort_by(|b1, b2| b1.block_id.cmp(&b2.block_id));
-        v
-    }
-}
-
+use crate::model::blocks_map::{BlocksMap, Block, TILE_SIZE};
+use crate::model::colors::{GLYPH, RGB};
+use crate::model::layout::GLYPH_SIZE;
 
 pub fn do_load_async(path: &str, tx: UnboundedSender<DBMessage>, progress: Arc<Mutex<f32>>) {
     let path = path.to_string();
@@ -90,122 +30,234 @@ pub fn do_load_async(path: &str, tx: UnboundedSender<DBMessage>, progress: Arc<M
 pub fn load_model_into(model: &mut Model, image: ImageView<'_>, on_progress: impl Fn(f32)) {
 
     let start_time = SystemTime::now();
-    let mut next_block_id = 0;
-    let mut connection_map: HashMap<usize, usize> = HashMap::new();
-    let mut fields: Vec<Record> = vec![];
-    let mut blocks_map = BlocksMap::new();
+    let all = |_x, _y| true;
+    let area = [(Vector2D::new(1, 1), Vector2D::new(image.width - 1, image.height - 1))];
 
-    let is_blank = |x, y| {
-        (x >= image.width || y >= image.height) || image.get_pixel(x, y).is_blank()
-    };
+    let fields = find_blocks(&image, &area, default_type(&image), |p| on_progress(0.33 * p));
+    let blocks_map = map_blocks(&fields);
+    let connection_map = find_connections(&image, &blocks_map, all, |p| on_progress(0.33 + 0.33 * p)).unwrap();
+    let mut records = put_together(fields, &connection_map, |p| on_progress(0.67 + 0.16 * p));
+    resolve_references(&image, &mut records, |p| on_progress(0.84 + 0.16 * p));
+    model.set_records(records);
 
-    let is_meta = |x, y| {
-        (x < image.width && y < image.height) && image.get_pixel(x, y).is_meta()
-    };
+    model.loading_time = start_time.elapsed().unwrap();
+}
+
+// Reads again records in and around `changed_tiles` (column, row of TILE_SIZE tiles), other records are kept.
+// The area grows until no record crosses its border. References of all records are resolved again,
+// their lines may go through the changed tiles.
+pub fn reload_tiles_into(model: &mut Model, image: ImageView<'_>, changed_tiles: &[(u32, u32)]) {
+    let start_time = SystemTime::now();
+    let mut tiles: HashSet<(u32, u32)> = changed_tiles.iter().copied().collect();
+    let default_type = default_type(&image);
+
+    let new_records = loop {
+        // records partially in the area are read as a whole
+        loop {
+            let crossing: Vec<(u32, u32)> = model.records.iter()
+                .map(|r| tiles_around(Vector2D::new(r.position.x, r.position.y.saturating_sub(GLYPH_SIZE)), r.rb_position))
+                .filter(|rt| rt.iter().any(|t| tiles.contains(t)) && !rt.iter().all(|t| tiles.contains(t)))
+                .flatten()
+                .collect();
+            if crossing.is_empty() { break; }
+            tiles.extend(crossing);
+        }
 
-    let if_not_blank = |x, y, value| {
-        if is_blank(x, y) { 0 } else { value }
+        let mut sorted_tiles: Vec<(u32, u32)> = tiles.iter().copied().collect();
+        sorted_tiles.sort_by_key(|(x, y)| (*y, *x));
+        // block corner is recognized by pixels around it, so a pixel past the tile is checked too
+        let area: Vec<(Vector2D, Vector2D)> = sorted_tiles.iter()
+            .map(|(tx, ty)| (
+                Vector2D::new((tx * TILE_SIZE).max(1), (ty * TILE_SIZE).max(1)),
+                Vector2D::new(((tx + 1) * TILE_SIZE).min(image.width - 1), ((ty + 1) * TILE_SIZE).min(image.height - 1))
+            ))
+            .collect();
+        let fields = find_blocks(&image, &area, default_type, |_p| {});
+
+        let outside: Vec<(u32, u32)> = fields.iter()
+            .flat_map(|f| tiles_around(Vector2D::new(f.position.x, f.fields[0].type_start.y), f.rb_position))
+            .filter(|t| !tiles.contains(t))
+            .collect();
+        if !outside.is_empty() {
+            tiles.extend(outside);
+            continue;
+        }
+
+        let blocks_map = map_blocks(&fields);
+        match find_connections(&image, &blocks_map, |x, y| tiles.contains(&(x / TILE_SIZE, y / TILE_SIZE)), |_p| {}) {
+            Ok(connection_map) => break put_together(fields, &connection_map, |_p| {}),
+            // connection leads out of the area, it may join a record there
+            Err(p) => { tiles.insert((p.x / TILE_SIZE, p.y / TILE_SIZE)); }
+        }
     };
 
-    let default_type =
-        if_not_blank(0, 0, 0b100_000_000)
-            | if_not_blank(1, 0, 0b010_000_000)
-            | if_not_blank(2, 0, 0b001_000_000)
-            | if_not_blank(0, 1, 0b000_100_000)
-            | if_not_blank(1, 1, 0b000_010_000)
-            | if_not_blank(2, 1, 0b000_001_000)
-            | if_not_blank(0, 2, 0b000_000_100)
-            | if_not_blank(1, 2, 0b000_000_010)
-            | if_not_blank(2, 2, 0b000_000_001);
-
-    // find blocks
-    for y in 1..image.height {
-        for x in 1..image.width {
-            if is_meta(x, y) && is_blank(x, y - 1) && is_blank(x - 1, y - 1) &&
-                is_meta(x + 1, y) && is_meta(x, y + 1) && is_blank(x + 1, y - 1) &&
-                is_blank(x - 1, y) && is_blank(x - 1, y + 1) &&
-                blocks_map.get_block(x, y).is_none()  {
-                let top_left = Vector2D { x, y };
-
-                let mut right_bottom = top_left.clone();
-                while is_meta(right_bottom.x + 1, top_left.y) {
-                    right_bottom.x += 1
-                }
-                while is_meta(top_left.x, right_bottom.y + 1) {
-                    right_bottom.y += 1
-                }
+    let mut records: Vec<Record> = model.records.iter()
+        .filter(|r| !tiles.contains(&(r.position.x / TILE_SIZE, r.position.y / TILE_SIZE)))
+        .cloned()
+        .collect();
+    records.extend(new_records);
+    // same order as `load_model_into` gives
+    records.sort_by_key(|r| (r.position.y, r.position.x));
+    resolve_references(&image, &mut records, |_p| {});
+    model.set_records(records);
 
-                let mut data_top_left = top_left.clone();
-                while is_meta(data_top_left.x, data_top_left.y) && data_top_left.x < right_bottom.x && data_top_left.y < right_bottom.y {
-                    data_top_left.x += 1;
-                    data_top_left.y += 1;
-                }
-                let mut data_right_bottom = right_bottom.clone();
-                while is_meta(data_right_bottom.x, data_right_bottom.y) && data_right_bottom.x > data_top_left.x && data_right_bottom.y > data_top_left.y {
-                    data_right_bottom.x -= 1;
-                    data_right_bottom.y -= 1;
-                }
-                assert!(data_right_bottom.x >= data_top_left.x, "failed: {} > {}", data_right_bottom.x, data_top_left.x);
-                assert!(data_right_bottom.y >= data_top_left.y);
-
-                let type_start_point = Vector2D {
-                    x: right_bottom.x - 2,
-                    y: top_left.y - 3,
-                };
-                let mut ftype =
-                    if_not_blank(type_start_point.x + 0, type_start_point.y + 0, 0b100_000_000)
-                        | if_not_blank(type_start_point.x + 1, type_start_point.y + 0, 0b010_000_000)
-                        | if_not_blank(type_start_point.x + 2, type_start_point.y + 0, 0b001_000_000)
-                        | if_not_blank(type_start_point.x + 0, type_start_point.y + 1, 0b000_100_000)
-                        | if_not_blank(type_start_point.x + 1, type_start_point.y + 1, 0b000_010_000)
-                        | if_not_blank(type_start_point.x + 2, type_start_point.y + 1, 0b000_001_000)
-                        | if_not_blank(type_start_point.x + 0, type_start_point.y + 2, 0b000_000_100)
-                        | if_not_blank(type_start_point.x + 1, type_start_point.y + 2, 0b000_000_010)
-                        | if_not_blank(type_start_point.x + 2, type_start_point.y + 2, 0b000_000_001);
-
-                if ftype == DEFAULT_TYPE.0 {
-                    ftype = default_type
-                }
+    model.loading_time = start_time.elapsed().unwrap();
+}
+
+// Tiles with pixels from `from` to `to` and one pixel around
+fn tiles_around(from: Vector2D, to: Vector2D) -> Vec<(u32, u32)> {
+    let mut tiles = vec![];
+    for ty in from.y.saturating_sub(1) / TILE_SIZE..=(to.y + 1) / TILE_SIZE {
+        for tx in from.x.saturating_sub(1) / TILE_SIZE..=(to.x + 1) / TILE_SIZE {
+            tiles.push((tx, ty));
+        }
+    }
+    tiles
+}
+
+fn is_blank(image: &ImageView, x: u32, y: u32) -> bool {
+    (x >= image.width || y >= image.height) || image.get_pixel(x, y).is_blank()
+}
 
-                let column_pix = image.get_pixel(top_left.x, 0);
-                fields.push(Record {
-                    position: top_left,
-                    column: column_pix.to_hex_color(),
-                    rb_position: right_bottom,
-                    fields: vec![Field {
-                        field_type: FieldType(ftype),
-                        data_start: data_top_left,
-                        data_end: data_right_bottom,
-                        type_start: type_start_point,
-                        ref_to_record: None,
-                    }],
-                });
-                blocks_map.add(Block {
-                    block_id: next_block_id,
-                    x1: top_left.x,
-                    y1: top_left.y,
-                    x2: right_bottom.x,
-                    y2: right_bottom.y,
-                });
-
-                next_block_id += 1;
-                //println!("found block {:?}", top_left);
+fn is_meta(image: &ImageView, x: u32, y: u32) -> bool {
+    (x < image.width && y < image.height) && image.get_pixel(x, y).is_meta()
+}
+
+// Type from 3x3 pixels glyph, bits go row by row from the top-left pixel
+fn read_glyph(image: &ImageView, start: Vector2D) -> u16 {
+    (0..9).filter(|bit| !is_blank(image, start.x + bit % 3, start.y + bit / 3)).fold(0, |ftype, bit| ftype | (0b100_000_000 >> bit))
+}
+
+// glyph can be drawn with a marker color, e.g. to choose a font for the string field
+fn read_glyph_color(image: &ImageView, start: Vector2D) -> RGB {
+    (0..9)
+        .map(|bit| image.get_pixel(start.x + bit % 3, start.y + bit / 3))
+        .find(|pixel| !pixel.is_blank())
+        .unwrap_or(GLYPH)
+}
+
+// Glyph in the top-left corner of the image is the type of blocks with DEFAULT_TYPE glyph
+pub(crate) fn default_type(image: &ImageView) -> u16 {
+    read_glyph(image, Vector2D::new(0, 0))
+}
+
+// Frame of the block may be thicker than 1 pixel, data area starts where diagonal from the corner leaves it
+fn data_area(image: &ImageView, top_left: Vector2D, right_bottom: Vector2D) -> (Vector2D, Vector2D) {
+    let mut data_top_left = top_left;
+    while is_meta(image, data_top_left.x, data_top_left.y) && data_top_left.x < right_bottom.x && data_top_left.y < right_bottom.y {
+        data_top_left.x += 1;
+        data_top_left.y += 1;
+    }
+    let mut data_right_bottom = right_bottom;
+    while is_meta(image, data_right_bottom.x, data_right_bottom.y) && data_right_bottom.x > data_top_left.x && data_right_bottom.y > data_top_left.y {
+        data_right_bottom.x -= 1;
+        data_right_bottom.y -= 1;
+    }
+    assert!(data_right_bottom.x >= data_top_left.x, "failed: {} > {}", data_right_bottom.x, data_top_left.x);
+    assert!(data_right_bottom.y >= data_top_left.y);
+    (data_top_left, data_right_bottom)
+}
+
+// Blocks with top-left corner in `area` (rectangles, inclusive), each as a record with one field.
+// Sorted by position, index of the record is id of the block.
+fn find_blocks(image: &ImageView, area: &[(Vector2D, Vector2D)], default_type: u16, on_progress: impl Fn(f32)) -> Vec<Record> {
+    let mut fields: Vec<Record> = vec![];
+    let mut blocks_map = BlocksMap::new();
+    let is_blank = |x, y| is_blank(image, x, y);
+    let is_meta = |x, y| is_meta(image, x, y);
+
+    let rows_count: u32 = area.iter().map(|(from, to)| to.y + 1 - from.y).sum();
+    let mut rows_done = 0;
+    for (from, to) in area {
+        for y in from.y..=to.y {
+            for x in from.x..=to.x {
+                if is_meta(x, y) && is_blank(x, y - 1) && is_blank(x - 1, y - 1) &&
+                    is_meta(x + 1, y) && is_meta(x, y + 1) && is_blank(x + 1, y - 1) &&
+                    is_blank(x - 1, y) && is_blank(x - 1, y + 1) &&
+                    blocks_map.get_block(x, y).is_none() {
+                    let top_left = Vector2D { x, y };
+
+                    let mut right_bottom = top_left;
+                    while is_meta(right_bottom.x + 1, top_left.y) {
+                        right_bottom.x += 1
+                    }
+                    while is_meta(top_left.x, right_bottom.y + 1) {
+                        right_bottom.y += 1
+                    }
+
+                    let (data_top_left, data_right_bottom) = data_area(image, top_left, right_bottom);
+
+                    let type_start_point = Vector2D {
+                        x: right_bottom.x - 2,
+                        y: top_left.y - 3,
+                    };
+                    let mut ftype = read_glyph(image, type_start_point);
+                    if ftype == DEFAULT_TYPE.0 {
+                        ftype = default_type
+                    }
+
+                    let column_pix = image.get_pixel(top_left.x, 0);
+                    blocks_map.add(Block {
+                        block_id: fields.len(),
+                        x1: top_left.x,
+                        y1: top_left.y,
+                        x2: right_bottom.x,
+                        y2: right_bottom.y,
+                    });
+                    fields.push(Record {
+                        position: top_left,
+                        column: column_pix.to_hex_color(),
+                        rb_position: right_bottom,
+                        fields: vec![Field {
+                            field_type: FieldType(ftype),
+                            data_start: data_top_left,
+                            data_end: data_right_bottom,
+                            type_start: type_start_point,
+                            glyph_color: read_glyph_color(image, type_start_point),
+                            ref_to_record: None,
+                            block_start: top_left,
+                            block_end: right_bottom,
+                        }],
+                    });
+                    //println!("found block {:?}", top_left);
+                }
             }
+            image.optimize();
+            rows_done += 1;
+            on_progress(rows_done as f32 / rows_count as f32);
         }
-        image.optimize();
-        on_progress(0.33 * (y as f32) / (image.height as f32));
     }
+    fields.sort_by_key(|f| (f.position.y, f.position.x));
+    fields
+}
+
+fn map_blocks(fields: &[Record]) -> BlocksMap {
+    let mut blocks_map = BlocksMap::new();
+    for (block_id, rec) in fields.iter().enumerate() {
+        blocks_map.add(Block {
+            block_id,
+            x1: rec.position.x,
+            y1: rec.position.y,
+            x2: rec.rb_position.x,
+            y2: rec.rb_position.y,
+        });
+    }
+    blocks_map
+}
 
-    // find connections
-    //debug!("find connections");
+// Blocks connected with lines of meta color, maps id of the block to id of the previous block of the record.
+// Fails with the first meta pixel out of `in_area`.
+fn find_connections(image: &ImageView, blocks_map: &BlocksMap, in_area: impl Fn(u32, u32) -> bool, on_progress: impl Fn(f32)) -> Result<HashMap<usize, usize>, Vector2D> {
+    let mut connection_map: HashMap<usize, usize> = HashMap::new();
     let mut points_investigated: HashSet<Vector2D> = HashSet::new();
-    let blocks_count = blocks_map.get_blocks().len();
+    let blocks = blocks_map.get_blocks();
+    let blocks_count = blocks.len();
     let mut block_idx = 0;
-    for block in blocks_map.get_blocks() {
+    for block in blocks {
         //ok, here we go. start flood fill
         let mut points_to_investigate: Vec<Vector2D> = vec![];
 
-        const NOT_CONNECTED: usize = usize::max_value();
+        const NOT_CONNECTED: usize = usize::MAX;
         let connect_from_id = block.block_id;
         let mut connect_to_id = NOT_CONNECTED;
         for x in block.x1 - 1..=block.x2 + 1 {
@@ -222,8 +274,9 @@ pub fn load_model_into(model: &mut Model, image: ImageView<'_>, on_progress: imp
             let p = points_to_investigate.pop().unwrap();
             if points_investigated.contains(&p) { continue; }
             points_investigated.insert(p);
-            if !is_meta(p.x, p.y) { continue; }
+            if !is_meta(image, p.x, p.y) { continue; }
             if block.contains(p.x, p.y) { continue; }
+            if !in_area(p.x, p.y) { return Err(p); }
             //println!("check {:?}", p);
             match blocks_map.get_block(p.x, p.y) {
                 Some(block) => {
@@ -249,47 +302,88 @@ pub fn load_model_into(model: &mut Model, image: ImageView<'_>, on_progress: imp
             connection_map.insert(connect_to_id, connect_from_id);
         }
         image.optimize();
-        block_idx = block_idx + 1;
-        on_progress(0.33 + 0.33 * (block_idx as f32) / (blocks_count as f32));
+        block_idx += 1;
+        on_progress(block_idx as f32 / blocks_count as f32);
     }
+    Ok(connection_map)
+}
 
-    //println!("references");
-// process references
-    for idx in 0..fields.len() {
-        let rec = &fields[idx];
-        let field = &rec.fields[0];
-        if field.field_type != REFERENCE_TYPE { continue; }
+// Joins connected blocks into records, record is at its first block
+fn put_together(mut fields: Vec<Record>, connection_map: &HashMap<usize, usize>, on_progress: impl Fn(f32)) -> Vec<Record> {
+    let mut records = vec![];
+    let fields_count = fields.len();
+    for idx in (0..fields_count).rev() {
+        let mut record = fields.remove(idx);
+        match connection_map.get(&idx) {
+            None => records.push(record),
+            Some(from_idx) => {
+                let target_record = &mut fields[*from_idx];
+                target_record.rb_position.x = target_record.rb_position.x.max(record.rb_position.x);
+                target_record.rb_position.y = target_record.rb_position.y.max(record.rb_position.y);
+                target_record.fields.append(&mut record.fields)
+            }
+        }
+        on_progress((fields_count - idx) as f32 / fields_count as f32);
+    }
+    records.reverse();
+    records
+}
+
+// Makes the field an unresolved reference: it gets back data area and glyph color of its own block
+pub(crate) fn detach_reference(image: &ImageView, field: &mut Field) {
+    let (data_start, data_end) = data_area(image, field.block_start, field.block_end);
+    field.field_type = REFERENCE_TYPE;
+    field.data_start = data_start;
+    field.data_end = data_end;
+    field.glyph_color = read_glyph_color(image, field.type_start);
+    field.ref_to_record = None;
+}
+
+// Follows the line from the glyph of each reference field through pixels of the glyph color.
+// The field gets type and data area of the first other block the line leads to and points to the record of that block.
+// When there is none, the field is an unresolved reference with its own data area.
+fn resolve_references(image: &ImageView, records: &mut [Record], on_progress: impl Fn(f32)) {
+    let mut blocks_map = BlocksMap::new();
+    // (record index, field index) by block id
+    let mut blocks = vec![];
+    for (ri, rec) in records.iter().enumerate() {
+        for (fi, field) in rec.fields.iter().enumerate() {
+            blocks_map.add(Block {
+                block_id: blocks.len(),
+                x1: field.block_start.x,
+                y1: field.block_start.y,
+                x2: field.block_end.x,
+                y2: field.block_end.y,
+            });
+            blocks.push((ri, fi));
+        }
+    }
+
+    let references: Vec<(usize, usize)> = blocks.iter()
+        .copied()
+        .filter(|(ri, fi)| records[*ri].fields[*fi].is_reference())
+        .collect();
+    for (idx, (ri, fi)) in references.iter().copied().enumerate() {
+        let field = &records[ri].fields[fi];
         let start_point = Vector2D { x: field.type_start.x + 1, y: field.type_start.y + 1 };
         let color = image.get_pixel(start_point.x, start_point.y);
-//        println!("  reference from {:?} color={:?} ts={:?}", rec.position, color, field.type_start);
         let mut points_to_process = vec![start_point];
         let mut points_investigated = HashSet::new();
+        let mut found = None;
 
-        let from_field = rec.position;
-
-        while !points_to_process.is_empty() {
-            let p = points_to_process.pop().unwrap();
-            if points_investigated.contains(&p) { continue; }
-            points_investigated.insert(p);
+        while let Some(p) = points_to_process.pop() {
+            if !points_investigated.insert(p) { continue; }
             match blocks_map.get_block(p.x, p.y) {
                 Some(block) => {
-//                    println!("p {:?} {:?}", p, block);
-                    let found_rec = fields[block.block_id].clone();
-                    if found_rec.position != from_field {
-                        let field = &mut fields[idx].fields[0];
-                        let found_field = found_rec.fields[0].clone();
-                        //                      println!("found reference from {:?} to {:?}", from_field, found_field.data_start);
-                        field.field_type = found_field.field_type;
-                        field.data_start = found_field.data_start;
-                        field.data_end = found_field.data_end;
-                        field.ref_to_record = Some(found_rec.position);
+                    if blocks[block.block_id] != (ri, fi) {
+                        found = Some(blocks[block.block_id]);
                         break;
                     }
                 }
                 None => {
                     if image.get_pixel(p.x, p.y) == color {
-                        for dx in -4..4 as i32 {
-                            for dy in -4..4 as i32 {
+                        for dx in -4..4_i32 {
+                            for dy in -4..4_i32 {
                                 points_to_process.push(Vector2D { x: (p.x as i32 + dx) as u32, y: (p.y as i32 + dy) as u32 });
                             }
                         }
@@ -297,29 +391,21 @@ pub fn load_model_into(model: &mut Model, image: ImageView<'_>, on_progress: imp
                 }
             }
         }
-        image.optimize();
-        on_progress(0.67 + 0.16 * (idx as f32) / (fields.len() as f32));
-    }
-
 
-// and now all together
-
-    //println!("together");
-    let fields_count = fields.len();
-    for idx in (0..fields_count).rev() {
-        let mut record = fields.remove(idx as usize);
-//println!(" idx = {} fields = {} conn_from = {:?}", idx, record.fields.len(), connection_map.get(&idx) );
-        match connection_map.get(&idx) {
-            None => model.insert_record(0, &record),
-            Some(from_idx) => {
-                let mut target_record = &mut fields[*from_idx];
-                target_record.rb_position.x = target_record.rb_position.x.max(record.rb_position.x);
-                target_record.rb_position.y = target_record.rb_position.y.max(record.rb_position.y);
-                target_record.fields.append(&mut record.fields)
+        match found {
+            Some((found_ri, found_fi)) => {
+                let found_field = records[found_ri].fields[found_fi].clone();
+                let found_position = records[found_ri].position;
+                let field = &mut records[ri].fields[fi];
+                field.field_type = found_field.field_type;
+                field.data_start = found_field.data_start;
+                field.data_end = found_field.data_end;
+                field.glyph_color = found_field.glyph_color;
+                field.ref_to_record = Some(found_position);
             }
+            None => detach_reference(image, &mut records[ri].fields[fi])
         }
-        on_progress(0.84 + 0.16 * ((fields_count - idx) as f32) / (fields_count as f32));
+        image.optimize();
+        on_progress(idx as f32 / references.len() as f32);
     }
-
-    model.loading_time = start_time.elapsed().unwrap();
-}
\ No newline at end of file
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Block {
+    pub(crate) x1: u32,
+    pub(crate) y1: u32,
+    pub(crate) x2: u32,
+    pub(crate) y2: u32,
+    pub(crate) block_id: usize,
+}
+
+impl Block {
+    pub(crate) fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x1 && y >= self.y1 && x <= self.x2 && y <= self.y2
+    }
+}
+
+// Side of square tiles blocks are grouped by, also the unit of changes when the image is reloaded
+pub(crate) const TILE_SIZE: u32 = 1024;
+
+// TILE_SIZE x TILE_SIZE pixels tiles
+pub(crate) struct BlocksMap {
+    map: HashMap<(u32, u32), Vec<Block>>,
+}
+
+impl BlocksMap {
+    pub(crate) fn new() -> Self {
+        Self { map: HashMap::new() }
+    }
+
+    pub(crate) fn add(&mut self, block: Block) {
+        let x1 = block.x1 / TILE_SIZE;
+        let x2 = block.x2 / TILE_SIZE;
+        let y1 = block.y1 / TILE_SIZE;
+        let y2 = block.y2 / TILE_SIZE;
+        for x in x1..=x2 {
+            for y in y1..=y2 {
+                match self.map.entry((x, y)) {
+                    Entry::Occupied(mut vector) => {
+                        vector.get_mut().push(block.clone());
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(vec![block.clone()]);
+                    }
+                };
+            }
+        }
+    }
+
+    pub(crate) fn get_block(&self, x: u32, y: u32) -> Option<&Block> {
+        match self.map.get(&(x / TILE_SIZE, y / TILE_SIZE)) {
+            Some(vec) => vec.iter().find(|b| b.contains(x, y)),
+            None => None
+        }
+    }
+
+    // Of all blocks overlapping the rectangle returns the one which ends lower
+    pub(crate) fn get_lowest_intersecting(&self, x1: u32, y1: u32, x2: u32, y2: u32) -> Option<&Block> {
+        let mut found: Option<&Block> = None;
+        for x in x1 / TILE_SIZE..=x2 / TILE_SIZE {
+            for y in y1 / TILE_SIZE..=y2 / TILE_SIZE {
+                if let Some(vec) = self.map.get(&(x, y)) {
+                    for b in vec.iter().filter(|b| b.x1 <= x2 && b.x2 >= x1 && b.y1 <= y2 && b.y2 >= y1) {
+                        if found.is_none_or(|f| f.y2 < b.y2) {
+                            found = Some(b);
+                        }
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    pub(crate) fn get_blocks(&self) -> Vec<&Block> {
+        let mut v: Vec<&Block> = self.map.values()
+            .flatten()
+            .collect();
+
+        v.sort_by(|b1, b2| b1.block_id.cmp(&b2.block_id));
+        v
+    }
+}
@@ -27,9 +27,20 @@ impl RGB {
     pub fn to_hex_color(&self) -> String {
         format!("#{:02X?}{:02X?}{:02X?}", self.r, self.g, self.b)
     }
+
+    pub fn from_hex_color(s: &str) -> Option<RGB> {
+        if s.len() != 7 || !s.starts_with('#') || !s[1..].chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(RGB::from(&s.to_string()))
+    }
 }
 
 pub const BLANK: RGB = RGB { r: 255, g: 255, b: 255 };
+pub const META: RGB = RGB { r: 0xBA, g: 0xDB, b: 0xEE };
+pub const GLYPH: RGB = RGB { r: 0, g: 0, b: 0 };
+// glyph and line of reference fields made by api
+pub const REFERENCE: RGB = RGB { r: 0xA3, g: 0x49, b: 0xA4 };
 
 impl From<&String> for RGB {
     fn from(s: &String) -> Self {
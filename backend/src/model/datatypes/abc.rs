@@ -1,65 +1,99 @@
 use crate::model::model::{FieldType, Field, DataType, DataValue, DataError, IncompatibleError};
-use crate::io::bitmap_font::BitmapFont;
+use crate::io::bitmap_font::{BitmapFont, FontRegistry};
 use crate::image::ImageView;
+use crate::model::colors::RGB;
 
 pub const ABC_TYPE: FieldType = FieldType(0b_010_101_101);
 
 pub(crate) struct ABCDataType {
-    bitmap_font: BitmapFont,
+    fonts: FontRegistry,
 }
 
 impl ABCDataType {
-    pub(crate) fn new() -> ABCDataType {
+    pub(crate) fn new(default_font: &str) -> ABCDataType {
+        let mut fonts = FontRegistry::new();
+        if !fonts.set_default(default_font) {
+            log::error!("Unknown font {}, available fonts: {:?}", default_font, fonts.names());
+        }
         return ABCDataType {
-            bitmap_font: BitmapFont::open3x5("font1.png", "ABCDEFGHIJKLMNOPQRSTUVWXYZ1234567890_+-*")
+            fonts
         }
     }
+
+    pub(crate) fn font_marker(&self, font: &str) -> Option<RGB> {
+        self.fonts.get_marker(font)
+    }
 }
 
 impl DataType for ABCDataType {
 
-    fn read(&self, image: &ImageView, _: &Field) -> Result<DataValue, DataError> {
-        let mut chars: Vec<char> = vec![];
+    fn read(&self, image: &ImageView, field: &Field) -> Result<DataValue, DataError> {
+        let bitmap_font = self.fonts.get_by_marker(&field.glyph_color);
+        let mut string = String::new();
+        let mut previous_full = true;
 
-        let mut start_x = 0;
-        let mut start_y = 1;
-
-        while start_x < image.width && start_y < image.height {
-            let mut char = ' ';
-            'out: for dx in 0..=2 {
-                if start_x + dx >= image.width { continue }
-                for dy in -1..=2 as i32 {
-                    if start_y == 0 && dy < 0 { continue }
-                    if (start_y as i32 + dy) as u32  >= image.height { continue }
-                    match self.bitmap_font.get_char(image, start_x + dx, (start_y as i32 + dy) as u32) {
-                        Some(c) => {
-                            char = c;
-                            start_x += dx;
-                            start_x += self.bitmap_font.char_dimensions.0;
-                            start_y = (start_y as i32 + dy) as u32;
-                            //start_y += self.bitmap_font.char_dimensions.1;
-                            break 'out;
-                        }
-                        None => continue
-                    }
-                }
-            }
-            if char == ' ' {
-                start_x += self.bitmap_font.char_dimensions.0;
-                //start_y += self.bitmap_font.char_dimensions.1;
+        let mut line_y = 1;
+        while line_y < image.height {
+            let (line, full) = read_line(bitmap_font, image, line_y);
+            // text is wrapped on spaces: a line ended before the right edge had a space after it, a full one is
+            // continued by the next line, which starts with the space if there was one
+            if !previous_full && !line.is_empty() {
+                string.push(' ');
             }
-            chars.push(char);
+            string.push_str(line.as_str());
+            previous_full = full;
+            line_y += bitmap_font.line_step();
         }
 
-        let string: String = chars.iter().collect();
         let string = String::from(string.trim());
         Ok(DataValue::String { value: string })
     }
 
-    fn write(&self, image: &mut ImageView, _: &Field, value: DataValue) -> Result<(), DataError> {
+    fn write(&self, image: &mut ImageView, field: &Field, value: DataValue) -> Result<(), DataError> {
         let string = get_matched!(value, DataValue::String { value })?;
+        let bitmap_font = self.fonts.get_by_marker(&field.glyph_color);
+        // check before clearing, so too long value leaves the field as it was
+        bitmap_font.layout(1, 1, image.width, image.height, string.as_str())?;
         image.clear();
-        self.bitmap_font.put_string(image, 1, 1, string.as_str());
-        Ok(())
+        bitmap_font.put_string(image, 1, 1, string.as_str())
+    }
+}
+
+// Reads characters of one line starting at `line_y`, characters may jump a pixel up or down.
+// Returns the line without trailing spaces and whether there is no space left for one more character.
+fn read_line(bitmap_font: &BitmapFont, image: &ImageView, line_y: u32) -> (String, bool) {
+    let mut chars: Vec<char> = vec![];
+    let mut full = false;
+
+    let mut start_x = 0;
+    let mut start_y = line_y;
+
+    while start_x < image.width && start_y < image.height {
+        let mut char = ' ';
+        'out: for dx in 0..=2 {
+            if start_x + dx >= image.width { continue }
+            for dy in -1..=2 as i32 {
+                if start_y == 0 && dy < 0 { continue }
+                if (start_y as i32 + dy) as u32  >= image.height { continue }
+                match bitmap_font.get_char(image, start_x + dx, (start_y as i32 + dy) as u32) {
+                    Some(c) => {
+                        char = c;
+                        start_x += dx;
+                        full = start_x + bitmap_font.char_step() + bitmap_font.char_dimensions.0 > image.width;
+                        start_x += bitmap_font.char_dimensions.0;
+                        start_y = (start_y as i32 + dy) as u32;
+                        break 'out;
+                    }
+                    None => continue
+                }
+            }
+        }
+        if char == ' ' {
+            start_x += bitmap_font.char_dimensions.0;
+        }
+        chars.push(char);
     }
+
+    let line: String = chars.iter().collect();
+    (String::from(line.trim_end()), full)
 }
\ No newline at end of file
@@ -1,6 +1,8 @@
-use crate::model::model::{Field, FieldType, DataType, DataValue, DataError};
+use crate::model::model::{Field, FieldType, DataType, DataValue, DataError, IncompatibleError};
 use std::collections::HashSet;
 use crate::image::ImageView;
+use crate::model::colors::RGB;
+use crate::model::datatypes::data_color;
 
 pub const COUNTER_TYPE: FieldType = FieldType(0b_111_010_111);
 
@@ -23,9 +25,9 @@ impl DataType for CounterDataType {
                         used_pixels.insert((x,y));
                         if !image.get_pixel(x, y).is_data() { continue }
                         pixels_to_check.push((x+1, y));
-                        pixels_to_check.push((x-1, y));
+                        pixels_to_check.push((x.wrapping_sub(1), y));
                         pixels_to_check.push((x, y+1));
-                        pixels_to_check.push((x, y-1));
+                        pixels_to_check.push((x, y.wrapping_sub(1)));
                     }
                     domains_found += 1;
                 }
@@ -34,5 +36,22 @@ impl DataType for CounterDataType {
         Ok(DataValue::Int {value: domains_found})
     }
 
+    // Draws one pixel dot per unit, every second pixel in every second row, so dots never touch each other
+    fn write(&self, image: &mut ImageView, _: &Field, value: DataValue) -> Result<(), DataError> {
+        let counter = get_matched!(value, DataValue::Int { value })?;
+        if counter < 0 {
+            return Err(DataError::Incompatible(IncompatibleError::CannotParseValue(format!("Counter cannot be negative: {}", counter))));
+        }
+        let columns = image.width.div_ceil(2);
+        let rows = image.height.div_ceil(2);
+        if counter as u32 > columns * rows {
+            return Err(DataError::Incompatible(IncompatibleError::InvalidSize));
+        }
+        let color = data_color(image).unwrap_or(RGB::new(0, 0, 0));
+        image.clear();
+        for i in 0..counter as u32 {
+            image.set_pixel(2 * (i % columns), 2 * (i / columns), color)?;
+        }
+        Ok(())
+    }
 }
-
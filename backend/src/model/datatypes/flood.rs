@@ -1,5 +1,7 @@
-use crate::model::model::{Field, FieldType, DataType, DataValue, DataError};
+use crate::model::model::{Field, FieldType, DataType, DataValue, DataError, IncompatibleError};
 use crate::image::ImageView;
+use crate::model::colors::RGB;
+use crate::model::datatypes::data_color;
 
 pub const FLOOD_TYPE: FieldType = FieldType(0b_111_110_100);
 
@@ -20,5 +22,19 @@ impl DataType for FloodDataType {
         }
         Ok(DataValue::Float { value: (flood_pixels as f32) / (pixels as f32)})
     }
-}
 
+    // Fills pixels column by column from left to right, like a progress bar
+    fn write(&self, image: &mut ImageView, _: &Field, value: DataValue) -> Result<(), DataError> {
+        let float = get_matched!(value, DataValue::Float { value })?;
+        if !(0.0..=1.0).contains(&float) {
+            return Err(DataError::Incompatible(IncompatibleError::CannotParseValue(format!("Value shall be in 0..1 range: {}", float))));
+        }
+        let color = data_color(image).unwrap_or(RGB::new(0, 0, 0));
+        image.clear();
+        let pixels = (float * (image.width * image.height) as f32).round() as u32;
+        for i in 0..pixels {
+            image.set_pixel(i / image.height, i % image.height, color)?;
+        }
+        Ok(())
+    }
+}
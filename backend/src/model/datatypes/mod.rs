@@ -1,5 +1,6 @@
 use crate::model::model::{DataType, Field, DataError, DataValue, FieldType, IncompatibleError};
 use crate::image::ImageView;
+use crate::model::colors::RGB;
 
 pub(crate) mod boolean;
 pub(crate) mod flood;
@@ -12,6 +13,32 @@ pub(crate) mod pie;
 
 pub const DEFAULT_TYPE: FieldType = FieldType(0b_000_000_000);
 
+const TYPE_NAMES: [(FieldType, &str); 8] = [
+    (boolean::BOOL_TYPE, "boolean"),
+    (image::IMAGE_TYPE, "image"),
+    (flood::FLOOD_TYPE, "float"),
+    (abc::ABC_TYPE, "string"),
+    (color::COLOR_TYPE, "color"),
+    (counter::COUNTER_TYPE, "int"),
+    (pie::PIE_TYPE, "pie"),
+    (reference::REFERENCE_TYPE, "reference"),
+];
+
+impl FieldType {
+    // names are the same as "type" in json
+    pub fn name(&self) -> Option<&'static str> {
+        TYPE_NAMES.iter().find(|(ftype, _)| ftype == self).map(|(_, name)| *name)
+    }
+
+    pub fn by_name(name: &str) -> Option<FieldType> {
+        TYPE_NAMES.iter().find(|(_, n)| *n == name).map(|(ftype, _)| *ftype)
+    }
+
+    pub fn names() -> impl Iterator<Item=&'static str> {
+        TYPE_NAMES.iter().map(|(_, name)| *name)
+    }
+}
+
 const BOOLEAN_DT: boolean::BooleanDataType = boolean::BooleanDataType {};
 const IMAGE_DT: image::ImageDataType = image::ImageDataType {};
 const FLOOD_DT: flood::FloodDataType = flood::FloodDataType {};
@@ -25,12 +52,17 @@ pub struct DataTypes {
 
 impl DataTypes {
 
-    pub fn new() -> Self {
+    pub fn new(default_font: &str) -> Self {
         Self {
-            abc_data_type: abc::ABCDataType::new()
+            abc_data_type: abc::ABCDataType::new(default_font)
         }
     }
 
+    // Color of the type glyph which makes a string field use given font
+    pub fn font_marker(&self, font: &str) -> Option<RGB> {
+        self.abc_data_type.font_marker(font)
+    }
+
     pub fn read_casted(&self, image: &ImageView, field: &Field, ftype: FieldType) -> Result<DataValue, DataError> {
         match ftype {
             boolean::BOOL_TYPE => BOOLEAN_DT.read(image, field),
@@ -67,8 +99,17 @@ impl DataTypes {
         }
     }
 
-    #[allow(dead_code)]
-    pub fn get_preferred_type(&self, val: DataValue) -> Option<FieldType> {
+    // Value as it should be written into the field of given type, e.g. 1 is a fine float.
+    // Value is given back if it doesn't match the type.
+    pub fn conform(&self, value: DataValue, ftype: FieldType) -> Result<DataValue, DataValue> {
+        match (value, ftype) {
+            (DataValue::Int { value }, flood::FLOOD_TYPE) => Ok(DataValue::Float { value: value as f32 }),
+            (value, _) if self.get_preferred_type(&value) == Some(ftype) => Ok(value),
+            (value, _) => Err(value)
+        }
+    }
+
+    pub fn get_preferred_type(&self, val: &DataValue) -> Option<FieldType> {
         match val {
             DataValue::Boolean { .. } => Some(boolean::BOOL_TYPE),
             DataValue::Int { .. } => Some(counter::COUNTER_TYPE),
@@ -83,3 +124,15 @@ impl DataTypes {
     }
 }
 
+// Color of the first data pixel, used to keep the field look when value is redrawn
+pub(crate) fn data_color(image: &ImageView) -> Option<RGB> {
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let pixel = image.get_pixel(x, y);
+            if pixel.is_data() {
+                return Some(pixel);
+            }
+        }
+    }
+    None
+}
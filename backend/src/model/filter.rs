@@ -0,0 +1,130 @@
+use crate::model::model::DataValue;
+use crate::model::colors::RGB;
+use crate::model::legend::FieldRef;
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FilterOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+impl FromStr for FilterOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "eq" => Ok(FilterOp::Eq),
+            "ne" => Ok(FilterOp::Ne),
+            "lt" => Ok(FilterOp::Lt),
+            "le" => Ok(FilterOp::Le),
+            "gt" => Ok(FilterOp::Gt),
+            "ge" => Ok(FilterOp::Ge),
+            "contains" => Ok(FilterOp::Contains),
+            _ => Err(format!("Unknown filter operation {}, expected one of eq, ne, lt, le, gt, ge, contains", s))
+        }
+    }
+}
+
+// Predicate on the value of one field, value is kept as given and interpreted by the type of the field value.
+// Values which cannot be compared (e.g. "lt" for colors or "abc" for ints) don't match.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pub field: FieldRef,
+    pub op: FilterOp,
+    pub value: String,
+}
+
+// Parses "field:op:value", e.g. "2:eq:true" or "name:contains:SON"
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let field = parts.next().unwrap_or("");
+        let field = field.parse().map_err(|_| format!("Invalid field {} in filter {}", field, s))?;
+        let op = parts.next().ok_or(format!("No operation in filter {}", s))?.parse()?;
+        let value = parts.next().ok_or(format!("No value in filter {}", s))?.to_string();
+        Ok(Filter { field, op, value })
+    }
+}
+
+impl Filter {
+    pub fn matches(&self, value: &DataValue) -> bool {
+        let ordering = match (value, self.op) {
+            (DataValue::String { value }, FilterOp::Contains) => return value.contains(self.value.as_str()),
+            (_, FilterOp::Contains) => return false,
+            (DataValue::Null, _) => Some(Ordering::Equal).filter(|_| self.value == "null"),
+            (DataValue::Boolean { value }, _) => self.value.parse::<bool>().ok().map(|v| value.cmp(&v)),
+            (DataValue::Int { value }, _) => self.value.parse::<f64>().ok().and_then(|v| (*value as f64).partial_cmp(&v)),
+            (DataValue::Float { value }, _) => self.value.parse::<f64>().ok().and_then(|v| (*value as f64).partial_cmp(&v)),
+            (DataValue::String { value }, _) => Some(value.as_str().cmp(self.value.as_str())),
+            (DataValue::Color { value }, FilterOp::Eq) | (DataValue::Color { value }, FilterOp::Ne) => {
+                // '#' has to be escaped in url, so allow colors without it
+                let color = if self.value.starts_with('#') { self.value.clone() } else { format!("#{}", self.value) };
+                return RGB::from_hex_color(&color).is_some_and(|c| (c == *value) == (self.op == FilterOp::Eq));
+            }
+            _ => None
+        };
+        match ordering {
+            Some(ordering) => match self.op {
+                FilterOp::Eq => ordering == Ordering::Equal,
+                FilterOp::Ne => ordering != Ordering::Equal,
+                FilterOp::Lt => ordering == Ordering::Less,
+                FilterOp::Le => ordering != Ordering::Greater,
+                FilterOp::Gt => ordering == Ordering::Greater,
+                FilterOp::Ge => ordering != Ordering::Less,
+                FilterOp::Contains => false,
+            },
+            None => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(filter: &str, value: DataValue) -> bool {
+        filter.parse::<Filter>().unwrap().matches(&value)
+    }
+
+    #[test]
+    fn filters_are_parsed() {
+        let filter: Filter = "name:contains:a:b".parse().unwrap();
+        assert_eq!((filter.field, filter.op, filter.value.as_str()), (FieldRef::Name("name".to_string()), FilterOp::Contains, "a:b"));
+        assert_eq!("2:eq:".parse::<Filter>().unwrap().field, FieldRef::Index(2));
+        assert!("2:like:x".parse::<Filter>().unwrap_err().starts_with("Unknown filter operation like"));
+        assert_eq!("2:eq".parse::<Filter>().unwrap_err(), "No value in filter 2:eq");
+        assert_eq!(":eq:1".parse::<Filter>().unwrap_err(), "Invalid field  in filter :eq:1");
+    }
+
+    #[test]
+    fn numbers_are_compared_as_numbers() {
+        assert!(matches("0:gt:2", DataValue::Int { value: 10 }));
+        assert!(matches("0:le:2.5", DataValue::Int { value: 2 }));
+        assert!(matches("0:lt:0.5", DataValue::Float { value: 0.25 }));
+        assert!(matches("0:ne:1", DataValue::Float { value: 0.5 }));
+        assert!(!matches("0:eq:abc", DataValue::Int { value: 1 }));
+        assert!(!matches("0:ne:abc", DataValue::Int { value: 1 }));
+    }
+
+    #[test]
+    fn strings_booleans_colors_and_nulls() {
+        assert!(matches("0:contains:SON", DataValue::String { value: "JSON".to_string() }));
+        assert!(matches("0:lt:B", DataValue::String { value: "A".to_string() }));
+        assert!(!matches("0:contains:1", DataValue::Int { value: 1 }));
+        assert!(matches("0:eq:true", DataValue::Boolean { value: true }));
+        assert!(matches("0:eq:ED1C24", DataValue::Color { value: RGB::new(0xED, 0x1C, 0x24) }));
+        assert!(matches("0:ne:#000000", DataValue::Color { value: RGB::new(0xED, 0x1C, 0x24) }));
+        assert!(!matches("0:lt:#FFFFFF", DataValue::Color { value: RGB::new(0, 0, 0) }));
+        assert!(matches("0:eq:null", DataValue::Null));
+        assert!(!matches("0:eq:1", DataValue::Null));
+    }
+}
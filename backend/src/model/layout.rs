@@ -0,0 +1,112 @@
+use crate::model::model::{Record, Field, FieldType, Vector2D, DataError, IncompatibleError};
+use crate::model::colors::{RGB, META, GLYPH, BLANK, REFERENCE};
+use crate::model::datatypes::reference::REFERENCE_TYPE;
+use crate::model::references::trace_reference_line;
+use crate::image::ImageView;
+
+// type glyph is 3x3 and sits right above the block frame
+pub(crate) const GLYPH_SIZE: u32 = 3;
+// horizontal gap between blocks of one record, they are connected by a meta line
+pub(crate) const BLOCK_GAP: u32 = 6;
+// glyph must not touch the top-left corner of the frame, so data area is at least 3 pixels wide
+pub(crate) const MIN_DATA_WIDTH: u32 = 3;
+
+// Size of the area needed for record with given data areas, including type glyphs above it
+pub(crate) fn record_size(sizes: &[Vector2D]) -> Vector2D {
+    let width = sizes.iter().map(|s| s.x + 2).sum::<u32>() + BLOCK_GAP * (sizes.len().max(1) as u32 - 1);
+    let height = sizes.iter().map(|s| s.y + 2).max().unwrap_or(0) + GLYPH_SIZE;
+    Vector2D::new(width, height)
+}
+
+// Places blocks one after another from left to right, block frames are 1 pixel wide.
+// `position` is the top-left pixel of the first frame (type glyphs go above it).
+pub(crate) fn layout_record(position: Vector2D, column: &RGB, field_types: &[FieldType], sizes: &[Vector2D]) -> Result<Record, DataError> {
+    if field_types.is_empty() || field_types.len() != sizes.len() {
+        return Err(DataError::Incompatible(IncompatibleError::CannotParseValue(
+            format!("Expected same number of field types and sizes, got {} and {}", field_types.len(), sizes.len())
+        )));
+    }
+    if position.y < GLYPH_SIZE + 1 {
+        return Err(DataError::Incompatible(IncompatibleError::InvalidSize));
+    }
+    let mut fields = vec![];
+    let mut x = position.x;
+    let mut bottom = position.y;
+    for (ftype, size) in field_types.iter().zip(sizes) {
+        if size.x < MIN_DATA_WIDTH || size.y < 1 {
+            return Err(DataError::Incompatible(IncompatibleError::InvalidSize));
+        }
+        let frame_end = Vector2D::new(x + size.x + 1, position.y + size.y + 1);
+        fields.push(Field {
+            field_type: *ftype,
+            data_start: Vector2D::new(x + 1, position.y + 1),
+            data_end: Vector2D::new(frame_end.x - 1, frame_end.y - 1),
+            type_start: Vector2D::new(frame_end.x - 2, position.y - GLYPH_SIZE),
+            // reference field starts unresolved, its line is drawn when it gets pointed to a record
+            glyph_color: if *ftype == REFERENCE_TYPE { REFERENCE } else { GLYPH },
+            ref_to_record: None,
+            block_start: Vector2D::new(x, position.y),
+            block_end: frame_end,
+        });
+        bottom = bottom.max(frame_end.y);
+        x = frame_end.x + BLOCK_GAP + 1;
+    }
+    Ok(Record {
+        position,
+        fields,
+        column: column.to_hex_color(),
+        rb_position: Vector2D::new(x - BLOCK_GAP - 1, bottom),
+    })
+}
+
+// Draws frames, type glyphs, connections between blocks and column marker for the record made by `layout_record`.
+// Data areas are left blank.
+pub(crate) fn draw_record(image: &mut ImageView, record: &Record, column: &RGB) -> Result<(), DataError> {
+    image.set_pixel(record.position.x, 0, *column)?;
+    for (idx, field) in record.fields.iter().enumerate() {
+        let frame_start = Vector2D::new(field.data_start.x - 1, field.data_start.y - 1);
+        let frame_end = Vector2D::new(field.data_end.x + 1, field.data_end.y + 1);
+        for x in frame_start.x..=frame_end.x {
+            for y in frame_start.y..=frame_end.y {
+                let on_frame = x == frame_start.x || x == frame_end.x || y == frame_start.y || y == frame_end.y;
+                image.set_pixel(x, y, if on_frame { META } else { BLANK })?;
+            }
+        }
+        for bit in 0..9 {
+            if field.field_type.0 & (0b100_000_000 >> bit) != 0 {
+                image.set_pixel(field.type_start.x + bit % 3, field.type_start.y + bit / 3, field.glyph_color)?;
+            }
+        }
+        if let Some(next) = record.fields.get(idx + 1) {
+            // connect to the next block below its top-left corner, otherwise it won't be recognized as a block
+            for x in frame_end.x + 1..next.data_start.x - 1 {
+                image.set_pixel(x, frame_start.y + 2, META)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Blanks everything drawn for the record: blocks, type glyphs and lines of reference fields.
+// Line pixels for which `is_occupied` returns true belong to other records and are kept.
+pub(crate) fn erase_record(image: &mut ImageView, record: &Record, is_occupied: impl Fn(u32, u32) -> bool) {
+    let mut pixels = vec![];
+    for field in &record.fields {
+        if field.is_reference() {
+            pixels.append(&mut trace_reference_line(image, field, &is_occupied));
+        }
+        for dx in 0..GLYPH_SIZE {
+            for dy in 0..GLYPH_SIZE {
+                pixels.push(Vector2D::new(field.type_start.x + dx, field.type_start.y + dy));
+            }
+        }
+    }
+    for x in record.position.x..=record.rb_position.x {
+        for y in record.position.y..=record.rb_position.y {
+            pixels.push(Vector2D::new(x, y));
+        }
+    }
+    for p in pixels {
+        image.set_pixel(p.x, p.y, BLANK).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+use crate::model::model::{Model, Record, DataValue};
+use crate::model::colors::META;
+use crate::model::datatypes::DataTypes;
+use crate::image::{ImageView, BoxedStorableImage};
+use std::str::FromStr;
+use std::fmt::{Display, Formatter};
+
+// Records in META column (#BADBEE) are not data, they name other columns and their fields.
+// Legend record has a color field with the column color, a string field with the column name
+// and then string fields with names of the column fields, in order of field indexes.
+#[derive(Debug, Clone)]
+pub struct ColumnNames {
+    pub column: String,
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Legend {
+    pub columns: Vec<ColumnNames>,
+}
+
+// Field given by index or by name from the legend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldRef {
+    Index(u32),
+    Name(String),
+}
+
+impl FromStr for FieldRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("Empty field name".to_string());
+        }
+        Ok(s.parse().map_or(FieldRef::Name(s.to_string()), FieldRef::Index))
+    }
+}
+
+impl Display for FieldRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldRef::Index(idx) => write!(f, "{}", idx),
+            FieldRef::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl Legend {
+    pub fn new() -> Self {
+        Self { columns: vec![] }
+    }
+
+    // Legend records which cannot be decoded or have unexpected fields are skipped
+    pub(crate) fn read(model: &Model, image: &mut BoxedStorableImage, data_types: &DataTypes) -> Legend {
+        let mut legend = Legend::new();
+        for rec in model.records.iter().filter(|r| r.column == META.to_hex_color()) {
+            let mut values = rec.fields.iter().map(|field| {
+                let view = ImageView::new(image, field.data_start, field.data_end);
+                data_types.read(&view, field)
+            });
+            let column = match values.next() {
+                Some(Ok(DataValue::Color { value })) => value.to_hex_color(),
+                _ => continue
+            };
+            let name = match values.next() {
+                Some(Ok(DataValue::String { value })) if !value.is_empty() => value,
+                _ => continue
+            };
+            let fields = values.map(|value| match value {
+                Ok(DataValue::String { value }) => value,
+                _ => String::new()
+            }).collect();
+            legend.columns.push(ColumnNames { column, name, fields });
+        }
+        legend
+    }
+
+    pub fn column_name(&self, column: &str) -> Option<&str> {
+        self.columns.iter().find(|c| c.column == column).map(|c| c.name.as_str())
+    }
+
+    // Color of the column with given name, or the name itself if there is no such column
+    pub fn column_color<'a>(&'a self, name: &'a str) -> &'a str {
+        self.columns.iter().find(|c| c.name == name).map_or(name, |c| c.column.as_str())
+    }
+
+    pub fn field_name(&self, column: &str, idx: usize) -> Option<&str> {
+        self.columns.iter()
+            .find(|c| c.column == column)
+            .and_then(|c| c.fields.get(idx))
+            .filter(|name| !name.is_empty())
+            .map(|name| name.as_str())
+    }
+
+    pub fn field_index(&self, rec: &Record, field: &FieldRef) -> Option<usize> {
+        match field {
+            FieldRef::Index(idx) => Some(*idx as usize),
+            FieldRef::Name(name) => self.columns.iter()
+                .find(|c| c.column == rec.column)
+                .and_then(|c| c.fields.iter().position(|f| f == name))
+        }
+    }
+}
+
+impl Default for Legend {
+    fn default() -> Self {
+        Legend::new()
+    }
+}
@@ -2,4 +2,11 @@
 pub mod model;
 pub(crate) mod datatypes;
 pub mod async_model_reader;
-pub(crate) mod colors;
\ No newline at end of file
+pub mod colors;
+pub(crate) mod layout;
+pub(crate) mod references;
+pub(crate) mod blocks_map;
+pub(crate) mod allocator;
+pub mod filter;
+pub mod ordering;
+pub mod legend;
@@ -2,6 +2,13 @@ use crate::image::{ImageView};
 use std::collections::HashMap;
 use crate::model::colors::RGB;
 use std::time::Duration;
+use crate::model::datatypes::reference::REFERENCE_TYPE;
+use crate::model::layout::GLYPH_SIZE;
+use crate::model::async_model_reader::detach_reference;
+use crate::model::colors::META;
+use crate::model::legend::Legend;
+use crate::model::datatypes::DataTypes;
+use crate::image::BoxedStorableImage;
 
 #[derive(Eq, PartialEq, Debug, Copy, Clone)]
 pub struct FieldType(pub u16);
@@ -24,7 +31,11 @@ pub struct Field {
     pub data_start: Vector2D,
     pub(crate) data_end: Vector2D,
     pub(crate) type_start: Vector2D,
+    pub(crate) glyph_color: RGB,
     pub ref_to_record: Option<Vector2D>,
+    // frame of the field's own block, resolved reference keeps it while data area is of the referenced block
+    pub(crate) block_start: Vector2D,
+    pub(crate) block_end: Vector2D,
 }
 
 #[derive(Debug, Clone)]
@@ -41,29 +52,145 @@ pub struct Model {
     pub loading_time: Duration,
 
     by_id: HashMap<Vector2D, usize>,
+    // referenced record id -> (record id, field index) of fields referencing it
+    referrers: HashMap<Vector2D, Vec<(Vector2D, usize)>>,
+    // read on first use, dropped when a legend record or data referenced by it changes
+    legend: Option<Legend>,
 }
 
 impl Model {
     pub fn new() -> Self {
-        Self { records: vec![], by_id: HashMap::new(), loading_time: Duration::from_secs(0) }
+        Self { records: vec![], by_id: HashMap::new(), referrers: HashMap::new(), loading_time: Duration::from_secs(0), legend: None }
+    }
+
+    pub(crate) fn legend(&mut self, image: &mut BoxedStorableImage, data_types: &DataTypes) -> Legend {
+        if self.legend.is_none() {
+            self.legend = Some(Legend::read(self, image, data_types));
+        }
+        self.legend.clone().unwrap()
+    }
+
+    // To be called after writing fields of the record
+    pub(crate) fn record_written(&mut self, id: Vector2D) {
+        if self.in_legend(id) {
+            self.legend = None;
+        }
     }
 
     pub fn add_record(&mut self, rec: &Record) {
+        if rec.column == META.to_hex_color() {
+            self.legend = None;
+        }
         self.records.push(rec.clone());
         self.by_id.insert(rec.position, self.records.len() - 1);
+        self.index_references(rec);
+    }
+
+    // Replaces all records, indexes are built again
+    pub(crate) fn set_records(&mut self, records: Vec<Record>) {
+        self.records = Vec::with_capacity(records.len());
+        self.by_id.clear();
+        self.referrers.clear();
+        self.legend = None;
+        for rec in &records {
+            self.add_record(rec);
+        }
     }
 
     pub fn insert_record(&mut self, idx: usize, rec: &Record) {
+        if rec.column == META.to_hex_color() {
+            self.legend = None;
+        }
         self.records.insert(idx, rec.clone());
+        self.index_references(rec);
         //let's recalculate all...
         for idx in 0..self.records.len() {
             self.by_id.insert(self.records[idx].position, idx);
         }
     }
 
+    pub fn remove_record(&mut self, x: u32, y: u32) -> Option<Record> {
+        self.record_written(Vector2D::new(x, y));
+        let idx = self.by_id.remove(&Vector2D::new(x, y))?;
+        let rec = self.records.remove(idx);
+        for idx in idx..self.records.len() {
+            self.by_id.insert(self.records[idx].position, idx);
+        }
+        for target in rec.fields.iter().filter_map(|f| f.ref_to_record) {
+            if let Some(referrers) = self.referrers.get_mut(&target) {
+                referrers.retain(|(id, _)| *id != rec.position);
+            }
+        }
+        Some(rec)
+    }
+
+    // Turns fields referencing the record into unresolved references with their own data area (like loader does
+    // when line leads nowhere). Returns (record id, field index) of such fields.
+    pub fn detach_references_to(&mut self, image: &ImageView, id: Vector2D) -> Vec<(Vector2D, usize)> {
+        let mut detached = vec![];
+        for rec in &mut self.records {
+            for (fi, field) in rec.fields.iter_mut().enumerate() {
+                if field.ref_to_record == Some(id) {
+                    if rec.column == META.to_hex_color() {
+                        self.legend = None;
+                    }
+                    detach_reference(image, field);
+                    detached.push((rec.position, fi));
+                }
+            }
+        }
+        self.referrers.remove(&id);
+        detached
+    }
+
     pub fn get_by_id(&self, x: u32, y: u32) -> Option<&Record> {
         self.by_id.get(&Vector2D::new(x, y)).map(|i| &self.records[*i])
     }
+
+    // Points the field to the record like the loader does: it gets type and data area of the first field of `target`.
+    // Without target the field becomes an unresolved reference with its own data area.
+    pub(crate) fn set_reference(&mut self, image: &ImageView, id: Vector2D, fi: usize, target: Option<Vector2D>) {
+        self.record_written(id);
+        let target_field = target.and_then(|t| self.get_by_id(t.x, t.y)).map(|r| r.fields[0].clone());
+        let idx = self.by_id[&id];
+        let field = &mut self.records[idx].fields[fi];
+        let old_target = field.ref_to_record;
+        match target_field {
+            Some(target_field) => {
+                field.field_type = target_field.field_type;
+                field.data_start = target_field.data_start;
+                field.data_end = target_field.data_end;
+                field.glyph_color = target_field.glyph_color;
+                field.ref_to_record = target;
+            }
+            None => detach_reference(image, field)
+        }
+        if let Some(referrers) = old_target.and_then(|t| self.referrers.get_mut(&t)) {
+            referrers.retain(|referrer| *referrer != (id, fi));
+        }
+        if let Some(target) = self.records[idx].fields[fi].ref_to_record {
+            self.referrers.entry(target).or_default().push((id, fi));
+        }
+    }
+
+    // (record id, field index) of fields referencing the record
+    pub fn get_referrers(&self, x: u32, y: u32) -> &[(Vector2D, usize)] {
+        self.referrers.get(&Vector2D::new(x, y)).map_or(&[], |referrers| referrers.as_slice())
+    }
+
+    // Legend record or a record whose data is shown in a legend record
+    fn in_legend(&self, id: Vector2D) -> bool {
+        let is_meta = |id: &Vector2D| self.get_by_id(id.x, id.y).is_some_and(|r| r.column == META.to_hex_color());
+        is_meta(&id) || self.get_referrers(id.x, id.y).iter().any(|(referrer, _)| is_meta(referrer))
+    }
+
+    fn index_references(&mut self, rec: &Record) {
+        for (fi, field) in rec.fields.iter().enumerate() {
+            if let Some(target) = field.ref_to_record {
+                self.referrers.entry(target).or_default().push((rec.position, fi));
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -71,6 +198,8 @@ pub enum IncompatibleError {
     InvalidDataType,
     InvalidSize,
     CannotParseValue(String),
+    // value doesn't fit into the data area, capacity is in value units (e.g. characters)
+    TooLong { length: usize, capacity: usize },
 }
 
 #[derive(Debug)]
@@ -79,6 +208,10 @@ pub enum DataError {
     UnknownType(FieldType),
     NotImplemented,
     NotFound,
+    NoSpace,
+    // no place for the reference line from the record to the target
+    NoRoute(Vector2D, Vector2D),
+    IOError(String),
 }
 
 #[derive(Debug)]
@@ -119,7 +252,53 @@ impl From<DataError> for String {
             DataError::UnknownType(dt) => format!("Unknown data type {:?}", dt),
             DataError::NotImplemented => String::from("Not implemented!"),
             DataError::NotFound => String::from("Not found"),
+            DataError::NoSpace => String::from("No free space left in image"),
+            DataError::NoRoute(from, to) => format!("No place for the line from {}/{} to {}/{}", from.x, from.y, to.x, to.y),
+            DataError::IOError(e) => format!("IO error: {}", e),
+        }
+    }
+}
+
+impl Field {
+    // Width and height of the data area
+    pub fn data_size(&self) -> Vector2D {
+        Vector2D::new(self.data_end.x - self.data_start.x + 1, self.data_end.y - self.data_start.y + 1)
+    }
+
+    pub fn is_reference(&self) -> bool {
+        self.ref_to_record.is_some() || self.field_type == REFERENCE_TYPE
+    }
+}
+
+impl Record {
+    // Copy of the record with area starting at `from` moved to `to`.
+    // Reference fields keep data area of the referenced record.
+    pub(crate) fn moved(&self, from: Vector2D, to: Vector2D) -> Record {
+        let shift = |v: Vector2D| Vector2D::new(v.x - from.x + to.x, v.y - from.y + to.y);
+        let mut moved = self.clone();
+        moved.position = shift(self.position);
+        moved.rb_position = shift(self.rb_position);
+        for field in &mut moved.fields {
+            field.type_start = shift(field.type_start);
+            field.block_start = shift(field.block_start);
+            field.block_end = shift(field.block_end);
+            if field.ref_to_record.is_none() {
+                field.data_start = shift(field.data_start);
+                field.data_end = shift(field.data_end);
+            }
         }
+        moved
+    }
+
+    // Bounding box including type glyphs above the blocks
+    pub(crate) fn contains_with_glyphs(&self, x: u32, y: u32) -> bool {
+        x >= self.position.x && x <= self.rb_position.x && y + GLYPH_SIZE >= self.position.y && y <= self.rb_position.y
+    }
+}
+
+impl From<std::io::Error> for DataError {
+    fn from(e: std::io::Error) -> Self {
+        DataError::IOError(e.to_string())
     }
 }
 
@@ -0,0 +1,68 @@
+use crate::model::model::DataValue;
+use crate::model::colors::RGB;
+use std::cmp::Ordering;
+
+// Values of different types are ordered by type, ints and floats are compared as numbers
+fn type_rank(value: &DataValue) -> u8 {
+    match value {
+        DataValue::Boolean { .. } => 0,
+        DataValue::Int { .. } | DataValue::Float { .. } => 1,
+        DataValue::String { .. } => 2,
+        DataValue::Color { .. } => 3,
+        DataValue::Image { .. } => 4,
+        DataValue::Histogram { .. } => 5,
+        DataValue::Custom { .. } => 6,
+        DataValue::Null => 7,
+    }
+}
+
+fn as_number(value: &DataValue) -> f64 {
+    match value {
+        DataValue::Int { value } => *value as f64,
+        DataValue::Float { value } => *value as f64,
+        _ => 0.0
+    }
+}
+
+fn color_key(color: &RGB) -> (u8, u8, u8) {
+    (color.r, color.g, color.b)
+}
+
+// Total ordering of values, nulls are the last
+pub fn compare(a: &DataValue, b: &DataValue) -> Ordering {
+    match (a, b) {
+        (DataValue::Boolean { value: a }, DataValue::Boolean { value: b }) => a.cmp(b),
+        (DataValue::Int { value: a }, DataValue::Int { value: b }) => a.cmp(b),
+        (DataValue::Int { .. } | DataValue::Float { .. }, DataValue::Int { .. } | DataValue::Float { .. }) =>
+            as_number(a).total_cmp(&as_number(b)),
+        (DataValue::String { value: a }, DataValue::String { value: b }) => a.cmp(b),
+        (DataValue::Color { value: a }, DataValue::Color { value: b }) => color_key(a).cmp(&color_key(b)),
+        (DataValue::Image { width: aw, height: ah, data_url: a }, DataValue::Image { width: bw, height: bh, data_url: b }) =>
+            (aw * ah, a).cmp(&(bw * bh, b)),
+        (DataValue::Histogram { value: a }, DataValue::Histogram { value: b }) => {
+            let sorted = |h: &std::collections::HashMap<RGB, f32>| {
+                let mut entries: Vec<((u8, u8, u8), f32)> = h.iter().map(|(c, v)| (color_key(c), *v)).collect();
+                entries.sort_by_key(|(c, _)| *c);
+                entries
+            };
+            let (a, b) = (sorted(a), sorted(b));
+            a.iter().zip(b.iter())
+                .map(|((ac, av), (bc, bv))| ac.cmp(bc).then(av.total_cmp(bv)))
+                .find(|o| *o != Ordering::Equal)
+                .unwrap_or(a.len().cmp(&b.len()))
+        }
+        (DataValue::Custom { subtype: at, value: a }, DataValue::Custom { subtype: bt, value: b }) => (at, a).cmp(&(bt, b)),
+        _ => type_rank(a).cmp(&type_rank(b))
+    }
+}
+
+// Ordering for sorting in given direction, nulls are the last in both directions
+pub fn compare_nulls_last(a: &DataValue, b: &DataValue, desc: bool) -> Ordering {
+    match (a, b) {
+        (DataValue::Null, DataValue::Null) => Ordering::Equal,
+        (DataValue::Null, _) => Ordering::Greater,
+        (_, DataValue::Null) => Ordering::Less,
+        _ if desc => compare(b, a),
+        _ => compare(a, b)
+    }
+}
@@ -0,0 +1,195 @@
+use crate::model::model::{Field, Record, Vector2D};
+use crate::image::ImageView;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// `load_model_into` looks for blocks and pixels of the line color this far around each line pixel
+const REACH: u32 = 4;
+// rows 0..3 keep column markers and default type glyph
+const RESERVED_ROWS: u32 = 3;
+// pixels around the glyph and the target first searched for the reference line
+const ROUTE_MARGIN: u32 = 64;
+
+// Pixel the reference line starts from: center of the type glyph. Its color is the color of the line.
+pub(crate) fn line_start(field: &Field) -> Vector2D {
+    Vector2D::new(field.type_start.x + 1, field.type_start.y + 1)
+}
+
+// Collects pixels of the reference line the same way `load_model_into` follows it:
+// pixels of the line color with gaps up to 4 pixels. Pixels for which `is_occupied` returns true
+// belong to records and are not followed.
+pub(crate) fn trace_reference_line(image: &ImageView, field: &Field, is_occupied: impl Fn(u32, u32) -> bool) -> Vec<Vector2D> {
+    let start_point = line_start(field);
+    let color = image.get_pixel(start_point.x, start_point.y);
+    let mut points_to_process = vec![start_point];
+    let mut points_investigated = HashSet::new();
+    let mut line = vec![];
+
+    while let Some(p) = points_to_process.pop() {
+        if !points_investigated.insert(p) { continue; }
+        if p.x >= image.width || p.y >= image.height || image.get_pixel(p.x, p.y) != color { continue; }
+        if is_occupied(p.x, p.y) { continue; }
+        line.push(p);
+        for dx in -4..4_i32 {
+            for dy in -4..4_i32 {
+                points_to_process.push(Vector2D { x: (p.x as i32 + dx) as u32, y: (p.y as i32 + dy) as u32 });
+            }
+        }
+    }
+    line
+}
+
+fn in_glyph(field: &Field, p: Vector2D) -> bool {
+    p.x >= field.type_start.x && p.x < field.type_start.x + 3 && p.y >= field.type_start.y && p.y < field.type_start.y + 3
+}
+
+// Frames of other blocks of the record
+fn sibling_frames<'a>(rec: &'a Record, field: &'a Field) -> impl Iterator<Item=(Vector2D, Vector2D)> + 'a {
+    rec.fields.iter()
+        .filter(move |f| f.type_start != field.type_start)
+        .map(|f| (f.block_start, f.block_end))
+}
+
+// Pixels of blocks, `load_model_into` doesn't follow the line through them.
+// Reference glyph may be drawn between blocks of its record, so the bounding box of `rec` is not a block.
+fn in_blocks(records: &[Record], rec: &Record, field: &Field, p: Vector2D) -> bool {
+    let in_record = |r: &Record| p.x >= r.position.x && p.x <= r.rb_position.x && p.y >= r.position.y && p.y <= r.rb_position.y;
+    records.iter().any(|r| r.position != rec.position && in_record(r))
+        || sibling_frames(rec, field).any(|(from, to)| p.x >= from.x && p.x <= to.x && p.y >= from.y && p.y <= to.y)
+}
+
+// Pixels of blocks and type glyphs other than the glyph of `field`
+fn is_occupied(records: &[Record], rec: &Record, field: &Field, p: Vector2D) -> bool {
+    in_blocks(records, rec, field, p)
+        || records.iter().flat_map(|r| &r.fields).any(|f| f.type_start != field.type_start && in_glyph(f, p))
+}
+
+// Pixels of the reference line of `field` of `rec` (glyph excluded), other `records` are occupied
+pub(crate) fn trace_own_reference_line(image: &ImageView, records: &[Record], rec: &Record, field: &Field) -> Vec<Vector2D> {
+    trace_reference_line(image, field, |x, y| is_occupied(records, rec, field, Vector2D::new(x, y)))
+        .into_iter()
+        .filter(|p| !in_glyph(field, *p))
+        .collect()
+}
+
+// Block at the top-left corner of the record. `load_model_into` takes type and data of the first block
+// the line leads to, so lines to the record end next to this one.
+fn first_block(target: &Record) -> (Vector2D, Vector2D) {
+    let p = target.position;
+    target.fields.iter()
+        .find(|f| p.x >= f.block_start.x && p.x <= f.block_end.x && p.y >= f.block_start.y && p.y <= f.block_end.y)
+        .map_or((p, p), |f| (f.block_start, f.block_end))
+}
+
+// Pixels to draw with the glyph color so `load_model_into` follows the line from the glyph of `field`
+// of `rec` to the `target` record and nowhere else. The line goes over blank pixels only and keeps REACH
+// from other blocks and from other pixels of its color (glyphs included). It ends next to the first block of `target`.
+// Shortest such line is returned, None if there is none. The line is looked for in the rectangle around the glyph
+// and the block, the rectangle is widened until it covers the whole image.
+pub(crate) fn route_reference_line(image: &ImageView, records: &[Record], rec: &Record, field: &Field, target: &Record) -> Option<Vec<Vector2D>> {
+    let start = line_start(field);
+    let (block_start, block_end) = first_block(target);
+    let mut margin = ROUTE_MARGIN;
+    loop {
+        let from = Vector2D::new(
+            start.x.min(block_start.x).saturating_sub(margin),
+            start.y.min(block_start.y).saturating_sub(margin),
+        );
+        let to = Vector2D::new(
+            start.x.max(block_end.x).saturating_add(margin).min(image.width - 1),
+            start.y.max(block_end.y).saturating_add(margin).min(image.height - 1),
+        );
+        if let Some(line) = route_in_area(image, records, rec, field, target, from, to) {
+            return Some(line);
+        }
+        if from == Vector2D::new(0, 0) && to == Vector2D::new(image.width - 1, image.height - 1) {
+            return None;
+        }
+        margin = margin.saturating_mul(2);
+    }
+}
+
+// Breadth-first search over pixels from `from` to `to` (inclusive)
+fn route_in_area(image: &ImageView, records: &[Record], rec: &Record, field: &Field, target: &Record, from: Vector2D, to: Vector2D) -> Option<Vec<Vector2D>> {
+    let start = line_start(field);
+    let color = image.get_pixel(start.x, start.y);
+    let in_area = |p: Vector2D| p.x >= from.x && p.x <= to.x && p.y >= from.y && p.y <= to.y;
+
+    // rectangles the line keeps out of, only those crossing the area
+    let mut blocked_areas: Vec<(Vector2D, Vector2D)> = vec![];
+    let mut block = |a: Vector2D, b: Vector2D, reach: u32| {
+        let a = Vector2D::new(a.x.saturating_sub(reach), a.y.saturating_sub(reach));
+        let b = Vector2D::new(b.x.saturating_add(reach), b.y.saturating_add(reach));
+        if a.x <= to.x && b.x >= from.x && a.y <= to.y && b.y >= from.y {
+            blocked_areas.push((a, b));
+        }
+    };
+    let (block_start, block_end) = first_block(target);
+    for r in records.iter().filter(|r| r.position != rec.position) {
+        let reach = if r.position == target.position { 0 } else { REACH };
+        block(r.position, r.rb_position, reach);
+    }
+    // and other blocks of the target, the line shall not lead to them first
+    for f in target.fields.iter().filter(|f| f.block_start != block_start) {
+        block(f.block_start, f.block_end, REACH);
+    }
+    for (a, b) in sibling_frames(rec, field) {
+        block(a, b, REACH);
+    }
+    // blank pixels of type glyphs are a part of the type too
+    for f in records.iter().flat_map(|r| &r.fields).filter(|f| f.type_start != field.type_start) {
+        block(f.type_start, Vector2D::new(f.type_start.x + 2, f.type_start.y + 2), 0);
+    }
+    block(Vector2D::new(0, 0), Vector2D::new(image.width - 1, RESERVED_ROWS), 0);
+
+    // pixels near other pixels of the line color, which may be just outside the area
+    let mut blocked_pixels: HashSet<Vector2D> = HashSet::new();
+    for y in from.y.saturating_sub(REACH)..=to.y.saturating_add(REACH).min(image.height - 1) {
+        for x in from.x.saturating_sub(REACH)..=to.x.saturating_add(REACH).min(image.width - 1) {
+            let p = Vector2D::new(x, y);
+            if image.get_pixel(x, y) == color && !in_glyph(field, p) && !in_blocks(records, rec, field, p) {
+                for yy in y.saturating_sub(REACH)..=y.saturating_add(REACH) {
+                    for xx in x.saturating_sub(REACH)..=x.saturating_add(REACH) {
+                        if in_area(Vector2D::new(xx, yy)) {
+                            blocked_pixels.insert(Vector2D::new(xx, yy));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let is_free = |p: Vector2D| {
+        in_glyph(field, p) || (
+            !blocked_pixels.contains(&p)
+                && !blocked_areas.iter().any(|(a, b)| p.x >= a.x && p.x <= b.x && p.y >= a.y && p.y <= b.y)
+                && image.get_pixel(p.x, p.y).is_blank()
+        )
+    };
+    let is_end = |p: Vector2D| {
+        p.x + 3 >= block_start.x && p.x <= block_end.x + REACH && p.y + 3 >= block_start.y && p.y <= block_end.y + REACH
+    };
+
+    let mut came_from: HashMap<Vector2D, Vector2D> = HashMap::new();
+    came_from.insert(start, start);
+    let mut queue = VecDeque::from(vec![start]);
+    while let Some(p) = queue.pop_front() {
+        if is_end(p) {
+            let mut path = vec![];
+            let mut p = p;
+            while p != start {
+                path.push(p);
+                p = came_from[&p];
+            }
+            path.reverse();
+            return Some(path.into_iter().filter(|p| !in_glyph(field, *p)).collect());
+        }
+        let neighbours = [(p.x.wrapping_sub(1), p.y), (p.x.wrapping_add(1), p.y), (p.x, p.y.wrapping_sub(1)), (p.x, p.y.wrapping_add(1))];
+        for (x, y) in neighbours {
+            let next = Vector2D::new(x, y);
+            if !in_area(next) || came_from.contains_key(&next) || !is_free(next) { continue; }
+            came_from.insert(next, p);
+            queue.push_back(next);
+        }
+    }
+    None
+}
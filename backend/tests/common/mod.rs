@@ -0,0 +1,88 @@
+// Helpers shared by the integration tests, each test file uses some of them
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use tempfile::TempDir;
+
+use badbee_backend::db::{DBHandle, DBQuery, DBResult, DataRecord, RecordsPage};
+use badbee_backend::model::colors::RGB;
+use badbee_backend::model::model::{FieldType, Vector2D};
+
+pub const RED: RGB = RGB { r: 0xED, g: 0x1C, b: 0x24 };
+pub const GREEN: RGB = RGB { r: 0x22, g: 0xB1, b: 0x4C };
+pub const BLUE: RGB = RGB { r: 0x3F, g: 0x48, b: 0xCC };
+
+// Blank white png database of given size in a new directory, opened in background.
+// The directory is removed when dropped.
+pub fn open_blank(width: u32, height: u32) -> (TempDir, String, DBHandle) {
+    open(image::RgbImage::from_pixel(width, height, image::Rgb([255, 255, 255])))
+}
+
+// Database of the image, see open_blank
+pub fn open(image: image::RgbImage) -> (TempDir, String, DBHandle) {
+    open_file(image, "db.png")
+}
+
+// Database of the image saved with the file name, the format is chosen by extension
+pub fn open_file(image: image::RgbImage, name: &str) -> (TempDir, String, DBHandle) {
+    // fonts are next to the workspace manifest
+    std::env::set_current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/..")).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(name);
+    image.save(&path).unwrap();
+    let path = path.to_str().unwrap().to_string();
+    let db = DBHandle::run_in_background(&path);
+    (dir, path, db)
+}
+
+// Database opened on a copy of the file in a new directory, copying it back imitates an outside edit
+pub fn open_copy(path: &str) -> (TempDir, String, DBHandle) {
+    let dir = tempfile::tempdir().unwrap();
+    let copy = dir.path().join(std::path::Path::new(path).file_name().unwrap());
+    std::fs::copy(path, &copy).unwrap();
+    let copy = copy.to_str().unwrap().to_string();
+    let db = DBHandle::run_in_background(&copy);
+    (dir, copy, db)
+}
+
+// Result of the query once the image is loaded
+pub async fn query(db: &DBHandle, query: DBQuery) -> DBResult<RecordsPage> {
+    loop {
+        match db.get_records(query.clone()).await {
+            DBResult::StillLoading(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            other => return other,
+        }
+    }
+}
+
+// Page of the query, panics on errors
+pub async fn page(db: &DBHandle, q: DBQuery) -> RecordsPage {
+    match query(db, q).await {
+        DBResult::Ok(page) => page,
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+// Completes once the image is loaded
+pub async fn loaded(db: &DBHandle) {
+    page(db, DBQuery::new().limit(0).build()).await;
+}
+
+// Records with given ids
+pub async fn records(db: &DBHandle, ids: Vec<Vector2D>) -> Vec<DataRecord> {
+    page(db, DBQuery::new().ids(ids).build()).await.records
+}
+
+// Id of a new record with fields of given type names and sizes
+pub async fn create(db: &DBHandle, column: RGB, fields: &[(&str, u32, u32)]) -> Vector2D {
+    match db.create_record(
+        column,
+        fields.iter().map(|(name, _, _)| FieldType::by_name(name).unwrap()).collect(),
+        fields.iter().map(|(_, w, h)| Vector2D::new(*w, *h)).collect(),
+        vec![None; fields.len()],
+    ).await {
+        DBResult::Ok(rec) => rec.id,
+        other => panic!("unexpected {:?}", other),
+    }
+}
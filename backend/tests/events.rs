@@ -0,0 +1,58 @@
+mod common;
+
+use std::time::Duration;
+
+use tokio::sync::broadcast::Receiver;
+
+use badbee_backend::db::{DBEvent, DBHandle};
+use badbee_backend::model::model::DataValue;
+use common::{create, loaded, open_blank, open_copy, GREEN, RED};
+
+async fn next(events: &mut Receiver<DBEvent>) -> DBEvent {
+    tokio::time::timeout(Duration::from_secs(5), events.recv()).await.expect("no event").unwrap()
+}
+
+// Changes made through the db and outside of it are announced to subscribers
+#[tokio::test]
+async fn changes_are_announced() {
+    let (_dir, path, db) = open_blank(200, 200);
+    loaded(&db).await;
+    let id = create(&db, RED, &[("int", 10, 10)]).await;
+    let mut events = db.subscribe();
+
+    db.set_field(id.x, id.y, 0, DataValue::Int { value: 2 }, "test".to_string(), None).await.unwrap();
+    assert!(matches!(next(&mut events).await, DBEvent::RecordChanged { id: changed } if changed == id));
+    let clone = db.clone_record(id.x, id.y, None).await.unwrap().id;
+    assert!(matches!(next(&mut events).await, DBEvent::RecordCreated { id: created } if created == clone));
+    db.delete_record(clone.x, clone.y).await.unwrap();
+    assert!(matches!(next(&mut events).await, DBEvent::RecordDeleted { id: deleted } if deleted == clone));
+    db.shutdown().await;
+
+    let db = DBHandle::run_in_background(&path);
+    loaded(&db).await;
+    let mut events = db.subscribe();
+    let (_copy_dir, copy_path, copy) = open_copy(&path);
+    loaded(&copy).await;
+    create(&copy, GREEN, &[("int", 10, 10)]).await;
+    copy.shutdown().await;
+    std::fs::copy(&copy_path, &path).unwrap();
+    db.sync().await;
+    assert!(matches!(next(&mut events).await, DBEvent::ModelReloaded));
+    // nothing else happened
+    assert!(events.try_recv().is_err());
+    db.shutdown().await;
+}
+
+// Rejected changes are not announced
+#[tokio::test]
+async fn rejected_changes_are_not_announced() {
+    let (_dir, _path, db) = open_blank(100, 100);
+    loaded(&db).await;
+    let id = create(&db, RED, &[("int", 10, 10)]).await;
+    let mut events = db.subscribe();
+    db.set_field(id.x, id.y, 0, DataValue::Int { value: 26 }, "test".to_string(), None).await;
+    db.set_field(id.x + 1, id.y, 0, DataValue::Int { value: 1 }, "test".to_string(), None).await;
+    db.delete_record(90, 90).await;
+    db.shutdown().await;
+    assert!(events.try_recv().is_err());
+}
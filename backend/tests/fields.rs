@@ -0,0 +1,38 @@
+mod common;
+
+use badbee_backend::db::DBResult;
+use badbee_backend::model::legend::FieldRef;
+use badbee_backend::model::model::DataValue;
+use common::{create, loaded, open_blank, records, RED};
+
+// No field is written when one of the fields is unknown
+#[tokio::test]
+async fn unknown_field_is_rejected_before_writing() {
+    let (_dir, _path, db) = open_blank(200, 200);
+    loaded(&db).await;
+    let id = create(&db, RED, &[("int", 10, 10)]).await;
+
+    for unknown in [FieldRef::Index(1), FieldRef::Name("price".to_string())] {
+        let values = vec![(FieldRef::Index(0), DataValue::Int { value: 7 }), (unknown.clone(), DataValue::Int { value: 1 })];
+        match db.set_fields(id.x, id.y, values, "test".to_string()).await {
+            DBResult::Invalid(invalid) => assert_eq!(invalid.field, unknown),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    assert_eq!(format!("{:?}", records(&db, vec![id]).await[0].fields[0].value), format!("{:?}", DataValue::Int { value: 0 }));
+    db.shutdown().await;
+}
+
+// Field index out of range is named in the error, not taken for a missing record
+#[tokio::test]
+async fn unknown_field_index_is_invalid() {
+    let (_dir, _path, db) = open_blank(200, 200);
+    loaded(&db).await;
+    let id = create(&db, RED, &[("int", 10, 10)]).await;
+    match db.set_field(id.x, id.y, 3, DataValue::Int { value: 1 }, "test".to_string(), None).await {
+        DBResult::Invalid(invalid) => assert_eq!((invalid.field, invalid.given.as_str()), (FieldRef::Index(3), "unknown field")),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(matches!(db.set_field(id.x + 1, id.y, 0, DataValue::Int { value: 1 }, "test".to_string(), None).await, DBResult::NotFound(_)));
+    db.shutdown().await;
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use badbee_backend::db::{DBHandle, DBQuery, DataRecord};
+use badbee_backend::model::model::DataValue;
+use common::{create, loaded, open_file, page, records, RED};
+
+// Records which didn't fit into the image go to its grown part, earlier records keep their ids and values
+async fn image_grows_keeping_records(name: &str) {
+    let (_dir, path, db) = open_file(image::RgbImage::from_pixel(40, 40, image::Rgb([255, 255, 255])), name);
+    loaded(&db).await;
+    let first = create(&db, RED, &[("int", 10, 10)]).await;
+    db.set_field(first.x, first.y, 0, DataValue::Int { value: 5 }, "test".to_string(), None).await.unwrap();
+
+    let mut ids = vec![first];
+    while ids.iter().all(|id| id.y < 40) {
+        assert!(ids.len() < 10, "image didn't grow");
+        ids.push(create(&db, RED, &[("int", 10, 10)]).await);
+    }
+    let last = *ids.last().unwrap();
+    db.set_field(last.x, last.y, 0, DataValue::Int { value: 7 }, "test".to_string(), None).await.unwrap();
+    let values = |records: Vec<DataRecord>| -> Vec<String> {
+        records.iter().map(|r| format!("{:?}", r.fields[0].value)).collect()
+    };
+    let expected = vec![format!("{:?}", DataValue::Int { value: 5 }), format!("{:?}", DataValue::Int { value: 7 })];
+    assert_eq!(values(records(&db, vec![first, last]).await), expected);
+    db.shutdown().await;
+
+    let reopened = DBHandle::run_in_background(&path);
+    assert_eq!(page(&reopened, DBQuery::new().build()).await.total, ids.len());
+    assert_eq!(values(records(&reopened, vec![first, last]).await), expected);
+    reopened.shutdown().await;
+}
+
+#[tokio::test]
+async fn png_grows_keeping_records() {
+    image_grows_keeping_records("db.png").await;
+}
+
+#[tokio::test]
+async fn bmp_grows_keeping_records() {
+    image_grows_keeping_records("db.bmp").await;
+}
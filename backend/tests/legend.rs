@@ -0,0 +1,56 @@
+mod common;
+
+use badbee_backend::db::{DBHandle, DBQuery};
+use badbee_backend::model::colors::{META, RGB};
+use badbee_backend::model::legend::FieldRef;
+use badbee_backend::model::model::{DataValue, Vector2D};
+use common::{create, loaded, open_blank, page, records, RED};
+
+// Legend record naming the column and its fields
+async fn name_column(db: &DBHandle, column: RGB, name: &str, fields: &[&str]) -> Vector2D {
+    let mut layout = vec![("color", 5, 5), ("string", 45, 7)];
+    layout.extend(fields.iter().map(|_| ("string", 45, 7)));
+    let id = create(db, META, &layout).await;
+    let mut values = vec![
+        (FieldRef::Index(0), DataValue::Color { value: column }),
+        (FieldRef::Index(1), DataValue::String { value: name.to_string() }),
+    ];
+    for (idx, field) in fields.iter().enumerate() {
+        values.push((FieldRef::Index(idx as u32 + 2), DataValue::String { value: field.to_string() }));
+    }
+    db.set_fields(id.x, id.y, values, "test".to_string()).await.unwrap();
+    id
+}
+
+#[tokio::test]
+async fn columns_and_fields_are_named_by_legend() {
+    let (_dir, _path, db) = open_blank(300, 300);
+    loaded(&db).await;
+    let young = create(&db, RED, &[("string", 45, 7), ("int", 10, 10)]).await;
+    let old = create(&db, RED, &[("string", 45, 7), ("int", 10, 10)]).await;
+    let legend = name_column(&db, RED, "PEOPLE", &["NAME", "AGE"]).await;
+
+    // fields are written and filtered by names
+    let set = |name: &str, age: i32| vec![
+        (FieldRef::Name("NAME".to_string()), DataValue::String { value: name.to_string() }),
+        (FieldRef::Name("AGE".to_string()), DataValue::Int { value: age }),
+    ];
+    db.set_fields(young.x, young.y, set("ANN", 3), "test".to_string()).await.unwrap();
+    db.set_fields(old.x, old.y, set("BOB", 9), "test".to_string()).await.unwrap();
+    let found = page(&db, DBQuery::new().column("PEOPLE".to_string()).filter("AGE:gt:5".parse().unwrap()).build()).await;
+    assert_eq!(found.records.iter().map(|r| r.id).collect::<Vec<_>>(), vec![old]);
+
+    // names are returned with records
+    let rec = &found.records[0];
+    assert_eq!(rec.column_name.as_deref(), Some("PEOPLE"));
+    assert_eq!(rec.fields.iter().map(|f| f.name.as_deref()).collect::<Vec<_>>(), vec![Some("NAME"), Some("AGE")]);
+
+    // legend records are listed only when their column is asked for
+    assert_eq!(page(&db, DBQuery::new().build()).await.total, 2);
+    assert_eq!(page(&db, DBQuery::new().column("#BADBEE".to_string()).build()).await.records[0].id, legend);
+
+    // renamed column is seen right away
+    db.set_fields(legend.x, legend.y, vec![(FieldRef::Index(1), DataValue::String { value: "CATS".to_string() })], "test".to_string()).await.unwrap();
+    assert_eq!(records(&db, vec![old]).await[0].column_name.as_deref(), Some("CATS"));
+    db.shutdown().await;
+}
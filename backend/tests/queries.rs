@@ -0,0 +1,91 @@
+mod common;
+
+use badbee_backend::db::{DBHandle, DBQuery, DBResult};
+use badbee_backend::model::legend::FieldRef;
+use badbee_backend::model::model::{DataValue, Vector2D};
+use common::{create, loaded, open_blank, page, query, RED};
+
+// New record with the int field set to the value
+async fn record_with(db: &DBHandle, value: i32) -> Vector2D {
+    let id = create(db, RED, &[("int", 10, 10)]).await;
+    db.set_field(id.x, id.y, 0, DataValue::Int { value }, "test".to_string(), None).await.unwrap();
+    id
+}
+
+#[tokio::test]
+async fn records_are_filtered_by_field_values() {
+    let (_dir, _path, db) = open_blank(300, 300);
+    loaded(&db).await;
+    let mut ids = vec![];
+    for value in [3, 1, 4, 2] {
+        ids.push(record_with(&db, value).await);
+    }
+
+    let found = page(&db, DBQuery::new().filter("0:ge:3".parse().unwrap()).build()).await;
+    assert_eq!(found.total, 2);
+    assert_eq!(found.records.iter().map(|r| r.id).collect::<Vec<_>>(), vec![ids[0], ids[2]]);
+    // all filters shall match
+    let found = page(&db, DBQuery::new().filter("0:ge:2".parse().unwrap()).filter("0:ne:3".parse().unwrap()).build()).await;
+    assert_eq!(found.records.iter().map(|r| r.id).collect::<Vec<_>>(), vec![ids[2], ids[3]]);
+    // field which the records don't have is null
+    assert_eq!(page(&db, DBQuery::new().filter("5:eq:null".parse().unwrap()).build()).await.total, 4);
+    db.shutdown().await;
+}
+
+// Ids of the page records
+async fn page_ids(db: &DBHandle, query: &mut DBQuery) -> Vec<Vector2D> {
+    page(db, query.build()).await.records.iter().map(|r| r.id).collect()
+}
+
+// Ordered by the field 1, records with only one field have null there
+#[tokio::test]
+async fn records_are_ordered_with_nulls_last_and_paged() {
+    let (_dir, _path, db) = open_blank(300, 300);
+    loaded(&db).await;
+    let mut with_values = vec![];
+    for value in [3, 1, 2] {
+        let id = create(&db, RED, &[("int", 10, 10), ("int", 10, 10)]).await;
+        db.set_field(id.x, id.y, 1, DataValue::Int { value }, "test".to_string(), None).await.unwrap();
+        with_values.push(id);
+    }
+    let null = record_with(&db, 5).await;
+    let (three, one, two) = (with_values[0], with_values[1], with_values[2]);
+
+    assert_eq!(page_ids(&db, DBQuery::new().order_by(FieldRef::Index(1), false)).await, vec![one, two, three, null]);
+    assert_eq!(page_ids(&db, DBQuery::new().order_by(FieldRef::Index(1), true)).await, vec![three, two, one, null]);
+    // offset and limit are applied to the ordered records
+    let second = page(&db, DBQuery::new().order_by(FieldRef::Index(1), false).offset(1).limit(2).build()).await;
+    assert_eq!((second.records.iter().map(|r| r.id).collect::<Vec<_>>(), second.total, second.offset), (vec![two, three], 4, 1));
+    assert!(page_ids(&db, DBQuery::new().offset(4)).await.is_empty());
+    // requested ids keep their order without order_by and are ordered with it
+    assert_eq!(page_ids(&db, DBQuery::new().ids(vec![null, three, one])).await, vec![null, three, one]);
+    assert_eq!(page_ids(&db, DBQuery::new().ids(vec![null, three, one]).order_by(FieldRef::Index(1), false)).await, vec![one, three, null]);
+    db.shutdown().await;
+}
+
+// Pages after a cursor record go on from its place even when records before it are deleted
+#[tokio::test]
+async fn records_are_paged_after_cursor() {
+    let (_dir, _path, db) = open_blank(300, 300);
+    loaded(&db).await;
+    let mut ids = vec![];
+    for value in [3, 1, 4, 2] {
+        ids.push(record_with(&db, value).await);
+    }
+    let (three, one, four, two) = (ids[0], ids[1], ids[2], ids[3]);
+
+    assert_eq!(page_ids(&db, DBQuery::new().after(one).limit(1)).await, vec![four]);
+    assert_eq!(page_ids(&db, DBQuery::new().order_by(FieldRef::Index(0), false).after(two).limit(5)).await, vec![three, four]);
+    assert_eq!(page_ids(&db, DBQuery::new().order_by(FieldRef::Index(0), true).after(three)).await, vec![two, one]);
+    assert_eq!(page_ids(&db, DBQuery::new().ids(vec![two, three, one]).after(three)).await, vec![one]);
+    assert!(matches!(query(&db, DBQuery::new().ids(vec![two]).after(three).build()).await, DBResult::BadRequest(_)));
+
+    db.delete_record(one.x, one.y).await.unwrap();
+    // place of a deleted record is still known by position, but not by value
+    assert_eq!(page_ids(&db, DBQuery::new().after(one)).await, vec![four, two]);
+    match query(&db, DBQuery::new().order_by(FieldRef::Index(0), false).after(one).build()).await {
+        DBResult::BadRequest(message) => assert!(message.contains("is deleted"), "{}", message),
+        other => panic!("unexpected {:?}", other),
+    }
+    db.shutdown().await;
+}
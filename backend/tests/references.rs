@@ -0,0 +1,162 @@
+mod common;
+
+use badbee_backend::db::{DBHandle, DBQuery, DBResult, DataRecord};
+use badbee_backend::model::colors::REFERENCE;
+use badbee_backend::model::model::Vector2D;
+use common::{create, loaded, open_blank, page, records, BLUE, GREEN, RED};
+
+// A reference set through the db is drawn as a line and found again when the image is loaded
+#[tokio::test]
+async fn reference_is_read_after_reload() {
+    let (_dir, path, db) = open_blank(200, 200);
+    loaded(&db).await;
+    let target = create(&db, RED, &[("int", 10, 10)]).await;
+    let id = create(&db, RED, &[("reference", 5, 5)]).await;
+
+    match db.set_reference(id.x, id.y, 0, Some(target)).await {
+        DBResult::Ok(()) => {}
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(records(&db, vec![id]).await[0].fields[0].reference, Some(target));
+    db.shutdown().await;
+
+    let reopened = DBHandle::run_in_background(&path);
+    assert_eq!(records(&reopened, vec![id]).await[0].fields[0].reference, Some(target));
+    reopened.shutdown().await;
+}
+
+#[tokio::test]
+async fn reference_errors() {
+    let (_dir, _path, db) = open_blank(200, 200);
+    loaded(&db).await;
+    let id = create(&db, RED, &[("reference", 5, 5)]).await;
+
+    assert!(matches!(db.set_reference(id.x, id.y, 0, Some(id)).await, DBResult::BadRequest(_)));
+    assert!(matches!(db.set_reference(id.x, id.y, 1, None).await, DBResult::NotFound(_)));
+    assert!(matches!(db.set_reference(id.x, id.y, 0, Some(Vector2D::new(150, 150))).await, DBResult::NotFound(_)));
+    assert!(matches!(db.set_reference(150, 150, 0, None).await, DBResult::NotFound(_)));
+    db.shutdown().await;
+}
+
+// A copy keeps the reference of the original
+#[tokio::test]
+async fn clone_keeps_reference() {
+    let (_dir, _path, db) = open_blank(200, 200);
+    loaded(&db).await;
+    let target = create(&db, RED, &[("int", 10, 10)]).await;
+    let id = create(&db, RED, &[("reference", 5, 5)]).await;
+    db.set_reference(id.x, id.y, 0, Some(target)).await.unwrap();
+
+    let copy = db.clone_record(id.x, id.y, None).await.unwrap();
+    assert_ne!(copy.id, id);
+    assert_eq!(copy.fields[0].reference, Some(target));
+    db.shutdown().await;
+}
+
+// When the line of the copy can't be drawn, there is no copy
+#[tokio::test]
+async fn clone_without_place_for_reference_fails() {
+    let (_dir, path, db) = open_blank(200, 200);
+    loaded(&db).await;
+    let target = create(&db, RED, &[("int", 10, 10)]).await;
+    let id = create(&db, RED, &[("reference", 5, 5)]).await;
+    db.set_reference(id.x, id.y, 0, Some(target)).await.unwrap();
+    db.shutdown().await;
+
+    // a ring of the line color around the target with a gap for the line, other lines keep away from both
+    let mut img = image::open(&path).unwrap().to_rgb8();
+    let color = image::Rgb([REFERENCE.r, REFERENCE.g, REFERENCE.b]);
+    let line: Vec<(u32, u32)> = img.enumerate_pixels().filter(|(_, _, pixel)| **pixel == color).map(|(x, y, _)| (x, y)).collect();
+    let (from, to) = (Vector2D::new(target.x.saturating_sub(7), target.y.saturating_sub(7)), Vector2D::new(target.x + 18, target.y + 18));
+    for y in from.y.max(4)..=to.y {
+        for x in from.x..=to.x {
+            let on_ring = x <= from.x + 1 || x + 1 >= to.x || y <= from.y + 1 || y + 1 >= to.y;
+            let near_line = line.iter().any(|(lx, ly)| lx.abs_diff(x) <= 4 && ly.abs_diff(y) <= 4);
+            if on_ring && !near_line {
+                img.put_pixel(x, y, color);
+            }
+        }
+    }
+    img.save(&path).unwrap();
+
+    let db = DBHandle::run_in_background(&path);
+    assert_eq!(records(&db, vec![id]).await[0].fields[0].reference, Some(target));
+    let before = page(&db, DBQuery::new().build()).await.total;
+    match db.clone_record(id.x, id.y, None).await {
+        DBResult::Err(error) => assert!(error.starts_with("No place for the line"), "{}", error),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(page(&db, DBQuery::new().build()).await.total, before);
+    db.shutdown().await;
+}
+
+// Referenced records are included up to the depth, a record is not included inside itself
+#[tokio::test]
+async fn references_are_expanded_until_cycle() {
+    let (_dir, path, db) = open_blank(300, 300);
+    loaded(&db).await;
+    // lines end next to the first block, records in own columns leave place for all of them
+    let layout = [("int", 20, 20), ("reference", 5, 5)];
+    let (a, b, c) = (create(&db, RED, &layout).await, create(&db, GREEN, &layout).await, create(&db, BLUE, &layout).await);
+    for (from, to) in [(a, b), (b, a), (c, a)] {
+        db.set_reference(from.x, from.y, 1, Some(to)).await.unwrap();
+    }
+
+    // record included into the reference field, if any
+    fn included(rec: &DataRecord) -> Option<&DataRecord> {
+        rec.fields[1].record.as_deref()
+    }
+    let expanded = |depth| DBQuery::new().ids(vec![c]).expand(depth).build();
+    let c_rec = &page(&db, expanded(5)).await.records[0];
+    let a_rec = included(c_rec).unwrap();
+    let b_rec = included(a_rec).unwrap();
+    assert_eq!((a_rec.id, b_rec.id), (a, b));
+    assert_eq!(b_rec.fields[1].reference, Some(a));
+    assert!(included(b_rec).is_none());
+
+    let c_rec = &page(&db, expanded(1)).await.records[0];
+    assert_eq!(included(c_rec).map(|r| r.id), Some(a));
+    assert!(included(included(c_rec).unwrap()).is_none());
+    assert!(included(&page(&db, expanded(0)).await.records[0]).is_none());
+    db.shutdown().await;
+
+    let reopened = DBHandle::run_in_background(&path);
+    let read: Vec<Option<Vector2D>> = records(&reopened, vec![a, b, c]).await.iter().map(|r| r.fields[1].reference).collect();
+    assert_eq!(read, vec![Some(b), Some(a), Some(a)]);
+    reopened.shutdown().await;
+}
+
+// Ids of records referencing the target with their referencing fields
+async fn referrers(db: &DBHandle, target: Vector2D) -> Vec<(Vector2D, Vec<usize>)> {
+    let mut found: Vec<(Vector2D, Vec<usize>)> = db.get_referrers(target.x, target.y).await.unwrap()
+        .into_iter().map(|r| (r.record.id, r.fields)).collect();
+    found.sort_by_key(|(id, _)| (id.x, id.y));
+    found
+}
+
+// Referrers are found after load and follow changes of references
+#[tokio::test]
+async fn referrers_are_indexed() {
+    let (_dir, path, db) = open_blank(300, 300);
+    loaded(&db).await;
+    let target = create(&db, RED, &[("int", 20, 20)]).await;
+    let one = create(&db, GREEN, &[("reference", 5, 5), ("int", 10, 10), ("reference", 5, 5)]).await;
+    let other = create(&db, BLUE, &[("reference", 5, 5)]).await;
+    for (from, fi) in [(one, 0), (one, 2), (other, 0)] {
+        db.set_reference(from.x, from.y, fi, Some(target)).await.unwrap();
+    }
+    db.shutdown().await;
+
+    let db = DBHandle::run_in_background(&path);
+    loaded(&db).await;
+    let mut expected = vec![(one, vec![0, 2]), (other, vec![0])];
+    expected.sort_by_key(|(id, _)| (id.x, id.y));
+    assert_eq!(referrers(&db, target).await, expected);
+
+    db.set_reference(one.x, one.y, 2, None).await.unwrap();
+    db.set_reference(other.x, other.y, 0, None).await.unwrap();
+    assert_eq!(referrers(&db, target).await, vec![(one, vec![0])]);
+    assert!(db.get_referrers(one.x, one.y).await.unwrap().is_empty());
+    assert!(matches!(db.get_referrers(target.x + 1, target.y).await, DBResult::NotFound(_)));
+    db.shutdown().await;
+}
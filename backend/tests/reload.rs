@@ -0,0 +1,45 @@
+mod common;
+
+use badbee_backend::db::{DBHandle, DBQuery, DataRecord};
+use badbee_backend::model::model::{DataValue, Vector2D};
+use common::{create, loaded, open_blank, open_copy, page, GREEN, RED};
+
+// Ids with values of all fields
+fn contents(records: &[DataRecord]) -> Vec<(Vector2D, Vec<String>)> {
+    records.iter().map(|r| (r.id, r.fields.iter().map(|f| format!("{:?}", f.value)).collect())).collect()
+}
+
+async fn all(db: &DBHandle) -> Vec<(Vector2D, Vec<String>)> {
+    contents(&page(db, DBQuery::new().build()).await.records)
+}
+
+// Image of the same size changed outside is read again by tiles, the result is the same as of a full load
+#[tokio::test]
+async fn changed_tiles_are_read_again() {
+    // two rows of tiles, the second record crosses their border
+    let (_dir, path, db) = open_blank(300, 1100);
+    loaded(&db).await;
+    let first = create(&db, RED, &[("int", 10, 600)]).await;
+    let second = create(&db, RED, &[("int", 10, 10), ("float", 10, 450)]).await;
+    db.shutdown().await;
+
+    let db = DBHandle::run_in_background(&path);
+    let before = all(&db).await;
+    let (_copy_dir, copy_path, copy) = open_copy(&path);
+    loaded(&copy).await;
+    copy.set_field(second.x, second.y, 1, DataValue::Float { value: 0.5 }, "test".to_string(), None).await.unwrap();
+    let third = create(&copy, GREEN, &[("int", 10, 10)]).await;
+    copy.shutdown().await;
+    std::fs::copy(&copy_path, &path).unwrap();
+
+    db.sync().await;
+    let after = all(&db).await;
+    assert_ne!(after, before);
+    let ids: Vec<Vector2D> = after.iter().map(|(id, _)| *id).collect();
+    assert!([first, second, third].iter().all(|id| ids.contains(id)));
+    db.shutdown().await;
+
+    let reopened = DBHandle::run_in_background(&path);
+    assert_eq!(all(&reopened).await, after);
+    reopened.shutdown().await;
+}
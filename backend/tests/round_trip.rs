@@ -0,0 +1,80 @@
+mod common;
+
+use badbee_backend::db::{DBHandle, DBResult, DataRecord};
+use badbee_backend::model::colors::RGB;
+use badbee_backend::model::model::{DataValue, FieldType, Vector2D};
+use common::{create, loaded, open, open_blank, records, RED};
+
+fn values(records: &[DataRecord]) -> Vec<String> {
+    records.iter().flat_map(|r| r.fields.iter()).map(|f| format!("{:?}", f.value)).collect()
+}
+
+// Fields written to a new record are read the same after the database is opened again
+#[tokio::test]
+async fn written_fields_are_read_after_reload() {
+    let (_dir, path, db) = open_blank(200, 200);
+    loaded(&db).await;
+    let id = create(&db, RED, &[("boolean", 5, 5), ("string", 45, 15), ("color", 5, 5), ("int", 10, 10)]).await;
+    let written = vec![
+        DataValue::Boolean { value: true },
+        DataValue::String { value: "HELLO WORLD".to_string() },
+        DataValue::Color { value: RGB::new(0, 0x80, 0xFF) },
+        DataValue::Int { value: 3 },
+    ];
+    let expected: Vec<String> = written.iter().map(|value| format!("{:?}", value)).collect();
+    for (fi, value) in written.into_iter().enumerate() {
+        match db.set_field(id.x, id.y, fi as u32, value, "test".to_string(), None).await {
+            DBResult::Ok(_) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    let before = records(&db, vec![id]).await;
+    assert_eq!(values(&before), expected);
+    db.shutdown().await;
+
+    let reopened = DBHandle::run_in_background(&path);
+    let after = records(&reopened, vec![id]).await;
+    assert_eq!(values(&after), expected);
+    assert_eq!(after[0].version, before[0].version);
+    reopened.shutdown().await;
+}
+
+// Types of a new record are read the same after the database is opened again
+#[tokio::test]
+async fn created_types_are_read_after_reload() {
+    let (_dir, path, db) = open_blank(200, 200);
+    loaded(&db).await;
+    let types = ["image", "boolean", "string", "color", "int", "float", "pie", "reference"];
+    let id = create(&db, RED, &types.map(|name| (name, 10, 10))).await;
+    db.shutdown().await;
+
+    let reopened = DBHandle::run_in_background(&path);
+    loaded(&reopened).await;
+    let model = reopened.get_model().await.unwrap();
+    let rec = model.records.iter().find(|r| r.position == id).unwrap();
+    let read: Vec<Option<&str>> = rec.fields.iter().map(|f| f.field_type.name()).collect();
+    assert_eq!(read, types.map(Some));
+    reopened.shutdown().await;
+}
+
+// Image glyph is empty, so image fields can't be created where the empty glyph means other type
+#[tokio::test]
+async fn image_field_is_rejected_when_empty_glyph_is_other_type() {
+    let mut image = image::RgbImage::from_pixel(200, 200, image::Rgb([255, 255, 255]));
+    // boolean glyph in the top-left corner
+    let boolean = FieldType::by_name("boolean").unwrap();
+    for bit in 0..9 {
+        if boolean.0 & (0b100_000_000 >> bit) != 0 {
+            image.put_pixel(bit % 3, bit / 3, image::Rgb([0, 0, 0]));
+        }
+    }
+    let (_dir, _path, db) = open(image);
+    loaded(&db).await;
+    let sizes = vec![Vector2D::new(10, 10)];
+    match db.create_record(RED, vec![FieldType::by_name("image").unwrap()], sizes.clone(), vec![None]).await {
+        DBResult::BadRequest(message) => assert!(message.contains("boolean"), "{}", message),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(matches!(db.create_record(RED, vec![boolean], sizes, vec![None]).await, DBResult::Ok(_)));
+    db.shutdown().await;
+}
@@ -0,0 +1,26 @@
+mod common;
+
+use badbee_backend::db::{DBResult, DataRecord};
+use badbee_backend::model::model::DataValue;
+use common::{create, loaded, open_blank, records, RED};
+
+fn value(records: &[DataRecord]) -> String {
+    format!("{:?}", records[0].fields[0].value)
+}
+
+// Restored snapshot brings back the values it was taken with, unknown snapshot is not found
+#[tokio::test]
+async fn snapshot_is_restored() {
+    let (_dir, _path, db) = open_blank(100, 100);
+    loaded(&db).await;
+    let id = create(&db, RED, &[("int", 10, 10)]).await;
+    db.set_field(id.x, id.y, 0, DataValue::Int { value: 1 }, "test".to_string(), None).await.unwrap();
+    let snapshot = db.create_snapshot(10).await.unwrap();
+    db.set_field(id.x, id.y, 0, DataValue::Int { value: 2 }, "test".to_string(), None).await.unwrap();
+
+    db.restore_snapshot(snapshot.id).await.unwrap();
+    assert_eq!(value(&records(&db, vec![id]).await), format!("{:?}", DataValue::Int { value: 1 }));
+    assert!(matches!(db.restore_snapshot(snapshot.id + 1).await, DBResult::NotFound(_)));
+    assert_eq!(db.get_snapshots().await.unwrap().len(), 1);
+    db.shutdown().await;
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use badbee_backend::db::{DBHandle, DBResult};
+use badbee_backend::model::model::{DataValue, FieldType, Vector2D};
+use common::{create, loaded, open_blank, records, RED};
+
+// Writes the value into the field 0, Debug of the read value or of the error
+async fn write_and_read(db: &DBHandle, id: Vector2D, value: DataValue) -> String {
+    match db.set_field(id.x, id.y, 0, value, "test".to_string(), None).await {
+        DBResult::Ok(_) => format!("{:?}", records(db, vec![id]).await[0].fields[0].value),
+        DBResult::Invalid(invalid) => format!("Invalid {}: {}", invalid.expected, invalid.given),
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+// Counter is a dot for each unit in every other pixel of every other row, 5x5 dots fit 10x10 area
+#[tokio::test]
+async fn counter_is_written_and_read() {
+    let (_dir, _path, db) = open_blank(100, 100);
+    loaded(&db).await;
+    let id = create(&db, RED, &[("int", 10, 10)]).await;
+    for value in [0, 1, 7, 25, 3] {
+        assert_eq!(write_and_read(&db, id, DataValue::Int { value }).await, format!("{:?}", DataValue::Int { value }));
+    }
+    assert_eq!(write_and_read(&db, id, DataValue::Int { value: 26 }).await, "Invalid int: value of invalid size");
+    assert!(write_and_read(&db, id, DataValue::Int { value: -1 }).await.starts_with("Invalid int: Counter cannot be negative"));
+    // rejected values leave the field as it was
+    assert_eq!(format!("{:?}", records(&db, vec![id]).await[0].fields[0].value), format!("{:?}", DataValue::Int { value: 3 }));
+    db.shutdown().await;
+}
+
+// Flood fills the share of pixels column by column, values outside of 0..1 are rejected
+#[tokio::test]
+async fn flood_is_written_and_read() {
+    let (_dir, _path, db) = open_blank(100, 100);
+    loaded(&db).await;
+    let id = create(&db, RED, &[("float", 10, 10)]).await;
+    for value in [0.0, 0.25, 1.0, 0.5] {
+        assert_eq!(write_and_read(&db, id, DataValue::Float { value }).await, format!("{:?}", DataValue::Float { value }));
+    }
+    // rounded to whole pixels
+    assert_eq!(write_and_read(&db, id, DataValue::Float { value: 0.333 }).await, format!("{:?}", DataValue::Float { value: 0.33 }));
+    for value in [1.5, -0.1] {
+        assert_eq!(write_and_read(&db, id, DataValue::Float { value }).await, format!("Invalid float: Value shall be in 0..1 range: {}", value));
+    }
+    assert_eq!(format!("{:?}", records(&db, vec![id]).await[0].fields[0].value), format!("{:?}", DataValue::Float { value: 0.33 }));
+    db.shutdown().await;
+}
+
+// String field created with 5x7 font takes lowercase and punctuation, default 3x5 font doesn't
+#[tokio::test]
+async fn string_is_written_in_field_font() {
+    let (_dir, _path, db) = open_blank(200, 100);
+    loaded(&db).await;
+    let types = vec![FieldType::by_name("string").unwrap(); 2];
+    let sizes = vec![Vector2D::new(80, 9); 2];
+    let rec = db.create_record(RED, types, sizes, vec![Some("5x7".to_string()), None]).await.unwrap();
+    let value = || DataValue::String { value: "Hello, world!".to_string() };
+    assert_eq!(write_and_read(&db, rec.id, value()).await, format!("{:?}", value()));
+    match db.set_field(rec.id.x, rec.id.y, 1, value(), "test".to_string(), None).await {
+        DBResult::Invalid(invalid) => assert!(invalid.given.contains("\"elo,wrd!\""), "{}", invalid.given),
+        other => panic!("unexpected {:?}", other),
+    }
+    db.shutdown().await;
+}
@@ -1,6 +1,7 @@
 use sauron::Cmd;
 use sauron::prelude::*;
-use sauron::prelude::web_sys::RequestInit;
+use sauron::prelude::web_sys::{RequestInit, Response, EventSource, MessageEvent};
+use sauron::prelude::wasm_bindgen::closure::Closure;
 use serde_derive::Deserialize;
 use crate::{App, Msg};
 
@@ -28,7 +29,11 @@ pub struct Model {
     pub(crate) loading_time: u32
 }
 
-pub type RecordsList = Vec<Record>;
+#[derive(Debug, Clone)]
+pub struct RecordsList {
+    pub(crate) records: Vec<Record>,
+    pub(crate) total: Option<u32>,
+}
 
 pub struct RecordsQuery {
     offset: Option<u32>, limit: Option<u32>, column: Option<String>, ids: Option<Vec<String>>
@@ -64,6 +69,19 @@ impl App {
         )
     }
 
+    // Changes made by others come as server-sent events, see `Msg::DBChanged`
+    pub fn listen_to_changes(&self, events: EventSource) -> Cmd<Self, Msg> {
+        Cmd::new(move |program| {
+            let listener: Closure<dyn FnMut(MessageEvent)> = Closure::wrap(Box::new(move |e: MessageEvent| {
+                program.dispatch(Msg::DBChanged { event: e.type_(), id: e.data().as_string().unwrap_or_default() })
+            }));
+            for event in &["record-changed", "record-created", "record-deleted", "model-reloaded"] {
+                events.add_event_listener_with_callback(event, listener.as_ref().unchecked_ref()).unwrap();
+            }
+            listener.forget();
+        })
+    }
+
     pub fn patch_record(&self, name: &String, rid: String, fid: u32, body: serde_json::Value) -> Cmd<Self, Msg> {
         Http::fetch_with_request_and_response_decoder(
             format!("{}/records/{}/{}", name, rid, fid).as_str(),
@@ -100,9 +118,20 @@ impl App {
             }
             url += "&";
         }
-        Http::fetch_with_text_response_decoder(
+        Http::fetch_with_request_and_response_decoder(
             url.as_str(),
-            |s| Msg::RecordsLoaded(Result::Ok(serde_json::from_str(&s).unwrap())),
+            None,
+            |(response, program): (Response, Program<Self, Msg>)| {
+                // number of all records matching the query is in the header, body has only the requested page
+                let total = response.headers().get("X-Total-Count").ok().flatten().and_then(|t| t.parse().ok());
+                let text_promise = response.text().expect("must be a promise text");
+                let dispatcher: Closure<dyn FnMut(JsValue)> = Closure::once(move |text: JsValue| {
+                    let records = serde_json::from_str(&text.as_string().unwrap()).unwrap();
+                    program.dispatch(Msg::RecordsLoaded(Result::Ok(RecordsList { records, total })));
+                });
+                let _ = text_promise.then(&dispatcher);
+                dispatcher.forget();
+            },
             |err| Msg::RecordsLoaded(Result::Err(err))
         )
     }
@@ -5,6 +5,7 @@ mod field_view;
 use sauron::html::text;
 use sauron::prelude::*;
 use sauron::js_sys::TypeError;
+use sauron::web_sys::EventSource;
 use sauron::{node, Cmd, Application, Node, Program};
 use crate::api::{Record, RecordsQuery};
 use record_view::record_view;
@@ -22,6 +23,8 @@ pub enum Msg {
     PrevPage, NextPage,
 
     PatchRequested { id: String, fi: usize, new_value: serde_json::Value },
+    // event is one of record-changed, record-created, record-deleted, model-reloaded
+    DBChanged { event: String, id: String },
 
     Noop
 }
@@ -34,7 +37,8 @@ pub struct App {
     loading_time: u32,
 
     records: Vec<Record>,
-    db_names: Vec<String>
+    db_names: Vec<String>,
+    events: Option<EventSource>,
 }
 
 
@@ -47,7 +51,8 @@ impl App {
             total: 0,
             loading_time: 0,
             records: vec![],
-            db_names: vec![]
+            db_names: vec![],
+            events: None,
         }
     }
 
@@ -81,11 +86,20 @@ impl Application<Msg> for App {
             }
             Msg::DBSelected(db_name) => {
                 self.db_name = Some(db_name.clone());
-                return self.fetch_db_info(db_name);
+                if let Some(events) = self.events.take() {
+                    events.close();
+                }
+                let events = EventSource::new(format!("{}/events", db_name).as_str()).unwrap();
+                self.events = Some(events.clone());
+                return Cmd::batch(vec![self.fetch_db_info(db_name), self.listen_to_changes(events)]);
             }
             Msg::RecordsLoaded(list) => {
                 //log::info!("{:?}", list);
-                self.records = list.unwrap()
+                let list = list.unwrap();
+                if let Some(total) = list.total {
+                    self.total = total;
+                }
+                self.records = list.records
             }
             Msg::DBRecordPatched(_result) => {
                 //log::info!("{:?}", result);
@@ -101,6 +115,12 @@ impl Application<Msg> for App {
             Msg::PatchRequested { id, fi, new_value } => {
                 return self.patch_record(self.db_name.as_ref().unwrap(), id, fi as u32, json!({"value": new_value}))
             }
+            Msg::DBChanged { event, id } => {
+                // changed record matters only when it is on the page, others may move records between pages
+                if event != "record-changed" || self.records.iter().any(|r| r.id == id) {
+                    return self.load_records();
+                }
+            }
         }
         Cmd::none().should_update_view(true)
     }
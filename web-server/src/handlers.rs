@@ -1,12 +1,23 @@
-use crate::{DBMAP, RecordsQuery};
-use warp::reply::{Json, with_status};
-use badbee_backend::db::{DBQuery, DataRecord, DBResult};
+use crate::{DBMAP, RecordsQuery, NewRecord};
+use warp::reply::{Json, with_status, WithStatus};
+use badbee_backend::db::{DBQuery, DataRecord, DBResult, InvalidValue, DBEvent};
 use crate::json::{to_json, from_json};
 use serde_json::{json, Value};
 use warp::{Reply, Rejection};
-use badbee_backend::model::model::Vector2D;
-use warp::http::StatusCode;
+use badbee_backend::model::model::{Vector2D, FieldType};
+use badbee_backend::model::colors::RGB;
+use badbee_backend::model::filter::Filter;
+use badbee_backend::model::legend::{FieldRef, Legend};
+use badbee_backend::io::snapshots::Snapshot;
+use badbee_backend::io::history::FieldChange;
+use warp::http::{StatusCode, HeaderValue};
+use warp::http::header::ETAG;
 use log::error;
+use warp::sse::Event;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use std::convert::Infallible;
 
 pub async fn get_dbs_handler(dbs: DBMAP) -> Result<impl Reply, Rejection> {
     let db_names: Vec<String> = dbs.lock().await.keys().map(|k| k.clone()).collect();
@@ -18,44 +29,183 @@ pub async fn get_model_handler(dbname: String, dbs: DBMAP) -> Result<Box<dyn Rep
         return Ok(Box::new(with_status("Unknown db", StatusCode::NOT_FOUND)));
     }
     let db = &dbs.lock().await[dbname.as_str()];
-    match db.get_model().await {
-        DBResult::Ok(model) => Ok(Box::new(warp::reply::json(&json!({
-            "loading_time": model.loading_time.as_millis() as u32,
-            "records": model.records.len(),
-            "fields_max": model.records.iter().map(|r| r.fields.len()).max(),
-            "fields_min": model.records.iter().map(|r| r.fields.len()).min(),
-        })))),
-        DBResult::StillLoading(progress) => {
-            Ok(
-                Box::new(with_status(format!("Still loading model ({}%)", (progress*100.0) as u32), StatusCode::PARTIAL_CONTENT))
-            )
+    Ok(reply_for(db.get_model().await, |model| Box::new(warp::reply::json(&json!({
+        "loading_time": model.loading_time.as_millis() as u32,
+        "records": model.records.len(),
+        "fields_max": model.records.iter().map(|r| r.fields.len()).max(),
+        "fields_min": model.records.iter().map(|r| r.fields.len()).min(),
+    })))))
+}
 
-        },
-        DBResult::Err(error) => {
-            error!("ERROR {}", error);
-            Ok(Box::new(with_status(error, StatusCode::INTERNAL_SERVER_ERROR)))
-        }
+// Records of one column with the same field types (and whether they are references)
+struct Layout {
+    fields: Vec<(FieldType, bool)>,
+    // the smallest data areas among the records, so any value fitting them fits all records
+    min_sizes: Vec<Vector2D>,
+    records: usize,
+}
+
+pub async fn get_schema_handler(dbname: String, dbs: DBMAP) -> Result<Box<dyn Reply>, Rejection> {
+    if !dbs.lock().await.contains_key(dbname.as_str()) {
+        return Ok(Box::new(with_status("Unknown db", StatusCode::NOT_FOUND)));
     }
+    let db = &dbs.lock().await[dbname.as_str()];
+    let legend = match db.get_legend().await {
+        DBResult::Ok(legend) => legend,
+        _ => Legend::new()
+    };
+    Ok(reply_for(db.get_model().await, |model| {
+        let mut columns: Vec<(String, Vec<Layout>)> = vec![];
+        for rec in &model.records {
+            let fields: Vec<(FieldType, bool)> = rec.fields.iter().map(|f| (f.field_type, f.is_reference())).collect();
+            let sizes: Vec<Vector2D> = rec.fields.iter().map(|f| f.data_size()).collect();
+            let idx = match columns.iter().position(|(c, _)| *c == rec.column) {
+                Some(idx) => idx,
+                None => {
+                    columns.push((rec.column.clone(), vec![]));
+                    columns.len() - 1
+                }
+            };
+            let layouts = &mut columns[idx].1;
+            match layouts.iter_mut().find(|l| l.fields == fields) {
+                Some(layout) => {
+                    for (min, size) in layout.min_sizes.iter_mut().zip(sizes) {
+                        *min = Vector2D::new(min.x.min(size.x), min.y.min(size.y));
+                    }
+                    layout.records += 1;
+                }
+                None => layouts.push(Layout { fields, min_sizes: sizes, records: 1 })
+            }
+        }
+        Box::new(warp::reply::json(&json!({
+            "columns": columns.iter().map(|(column, layouts)| json!({
+                "column": column,
+                "name": legend.column_name(column),
+                "records": layouts.iter().map(|l| l.records).sum::<usize>(),
+                "layouts": layouts.iter().map(|l| json!({
+                    "records": l.records,
+                    "fields": l.fields.iter().zip(&l.min_sizes).enumerate().map(|(idx, ((ftype, reference), size))| json!({
+                        "index": idx,
+                        "name": legend.field_name(column, idx),
+                        "type": ftype.name(),
+                        "width": size.x,
+                        "height": size.y,
+                        "reference": reference,
+                    })).collect::<Vec<Value>>()
+                })).collect::<Vec<Value>>()
+            })).collect::<Vec<Value>>()
+        })))
+    }))
 }
 
-pub async fn clone_record_handler(dbname: String, x: u32, y: u32, dbs: DBMAP) -> Result<Box<dyn Reply>, Rejection> {
+// With If-Match the record is cloned only if it wasn't changed since the client got it
+pub async fn clone_record_handler(dbname: String, x: u32, y: u32, dbs: DBMAP, if_match: Option<String>) -> Result<Box<dyn Reply>, Rejection> {
     if !dbs.lock().await.contains_key(dbname.as_str()) {
         return Ok(Box::new(with_status("Unknown db", StatusCode::NOT_FOUND)));
     }
+    let expected_versions = match parse_if_match(if_match) {
+        Ok(version) => version,
+        Err(error) => return Ok(Box::new(with_status(error, StatusCode::BAD_REQUEST)))
+    };
     let db = &dbs.lock().await[dbname.as_str()];
-    match db.clone_record(x, y).await {
-        DBResult::Ok(record) => Ok(Box::new(get_records_json(vec![record], false))),
-        DBResult::StillLoading(progress) => {
-            Ok(
-                Box::new(with_status(format!("Still loading model ({}%)", (progress*100.0) as u32), StatusCode::PARTIAL_CONTENT))
-            )
+    Ok(reply_for(db.clone_record(x, y, expected_versions).await, |record| Box::new(get_records_json(vec![record], false))))
+}
 
-        },
-        DBResult::Err(error) => {
-            error!("ERROR {}", error);
-            Ok(Box::new(with_status(error, StatusCode::INTERNAL_SERVER_ERROR)))
+pub async fn create_record_handler(dbname: String, dbs: DBMAP, new_record: NewRecord) -> Result<Box<dyn Reply>, Rejection> {
+    if !dbs.lock().await.contains_key(dbname.as_str()) {
+        return Ok(Box::new(with_status("Unknown db", StatusCode::NOT_FOUND)));
+    }
+    let column = match RGB::from_hex_color(&new_record.column) {
+        Some(column) => column,
+        None => return Ok(Box::new(with_status(format!("Invalid column color {}", new_record.column), StatusCode::BAD_REQUEST)))
+    };
+    let mut field_types = vec![];
+    for field in &new_record.fields {
+        match FieldType::by_name(&field.ftype) {
+            Some(ftype) => field_types.push(ftype),
+            None => return Ok(Box::new(with_status(format!("Unknown field type {}", field.ftype), StatusCode::BAD_REQUEST)))
         }
     }
+    let sizes = new_record.fields.iter().map(|f| Vector2D::new(f.width, f.height)).collect();
+    let fonts = new_record.fields.iter().map(|f| f.font.clone()).collect();
+    let db = &dbs.lock().await[dbname.as_str()];
+    Ok(reply_for(db.create_record(column, field_types, sizes, fonts).await, |record| Box::new(with_status(get_records_json(vec![record], false), StatusCode::CREATED))))
+}
+
+pub async fn delete_record_handler(dbname: String, x: u32, y: u32, dbs: DBMAP) -> Result<Box<dyn Reply>, Rejection> {
+    if !dbs.lock().await.contains_key(dbname.as_str()) {
+        return Ok(Box::new(with_status("Unknown db", StatusCode::NOT_FOUND)));
+    }
+    let db = &dbs.lock().await[dbname.as_str()];
+    Ok(reply_for(db.delete_record(x, y).await, |deleted| Box::new(warp::reply::json(&json!({
+        "id": vec2id(deleted.id),
+        "dangling_references": deleted.dangling_references.iter()
+            .map(|(id, fi)| json!({"record": vec2id(*id), "field": fi}))
+            .collect::<Vec<Value>>()
+    })))))
+}
+
+pub async fn create_snapshot_handler(dbname: String, dbs: DBMAP, keep: usize) -> Result<Box<dyn Reply>, Rejection> {
+    if !dbs.lock().await.contains_key(dbname.as_str()) {
+        return Ok(Box::new(with_status("Unknown db", StatusCode::NOT_FOUND)));
+    }
+    let db = &dbs.lock().await[dbname.as_str()];
+    Ok(reply_for(db.create_snapshot(keep).await, |snapshot| Box::new(with_status(warp::reply::json(&snapshot_json(&snapshot)), StatusCode::CREATED))))
+}
+
+pub async fn get_snapshots_handler(dbname: String, dbs: DBMAP) -> Result<Box<dyn Reply>, Rejection> {
+    if !dbs.lock().await.contains_key(dbname.as_str()) {
+        return Ok(Box::new(with_status("Unknown db", StatusCode::NOT_FOUND)));
+    }
+    let db = &dbs.lock().await[dbname.as_str()];
+    Ok(reply_for(db.get_snapshots().await, |snapshots| Box::new(warp::reply::json(&snapshots.iter().map(snapshot_json).collect::<Vec<Value>>()))))
+}
+
+pub async fn restore_snapshot_handler(dbname: String, id: u64, dbs: DBMAP) -> Result<Box<dyn Reply>, Rejection> {
+    if !dbs.lock().await.contains_key(dbname.as_str()) {
+        return Ok(Box::new(with_status("Unknown db", StatusCode::NOT_FOUND)));
+    }
+    let db = &dbs.lock().await[dbname.as_str()];
+    Ok(reply_for(db.restore_snapshot(id).await, |()| Box::new(warp::reply())))
+}
+
+// id is the creation time in milliseconds since the epoch
+fn snapshot_json(snapshot: &Snapshot) -> Value {
+    json!({
+        "id": snapshot.id,
+        "size": snapshot.size,
+    })
+}
+
+pub async fn get_history_handler(dbname: String, x: u32, y: u32, dbs: DBMAP) -> Result<Box<dyn Reply>, Rejection> {
+    if !dbs.lock().await.contains_key(dbname.as_str()) {
+        return Ok(Box::new(with_status("Unknown db", StatusCode::NOT_FOUND)));
+    }
+    let db = &dbs.lock().await[dbname.as_str()];
+    Ok(reply_for(db.get_history(x, y).await, |changes| Box::new(warp::reply::json(&changes.iter().map(field_change_json).collect::<Vec<Value>>()))))
+}
+
+// Field gets pixels it had before the change of the version
+pub async fn revert_field_handler(dbname: String, x: u32, y: u32, version: u64, dbs: DBMAP, client: String) -> Result<Box<dyn Reply>, Rejection> {
+    if !dbs.lock().await.contains_key(dbname.as_str()) {
+        return Ok(Box::new(with_status("Unknown db", StatusCode::NOT_FOUND)));
+    }
+    let db = &dbs.lock().await[dbname.as_str()];
+    Ok(reply_for(db.revert_field(x, y, version, client).await, |()| Box::new(warp::reply())))
+}
+
+// time is in milliseconds since the epoch, old and new are pixels of the field data area
+fn field_change_json(change: &FieldChange) -> Value {
+    json!({
+        "version": change.version,
+        "field": change.field,
+        "time": change.time,
+        "client": change.client,
+        "width": change.width(),
+        "height": change.height(),
+        "old": format!("data:image/png;base64,{}", change.old_base64()),
+        "new": format!("data:image/png;base64,{}", change.new_base64()),
+    })
 }
 
 pub async fn get_records_handler(dbname: String, q: RecordsQuery, dbs: DBMAP) -> Result<Box<dyn Reply>, Rejection> {
@@ -65,87 +215,364 @@ pub async fn get_records_handler(dbname: String, q: RecordsQuery, dbs: DBMAP) ->
     let db = &dbs.lock().await[dbname.as_str()];
     let mut query = DBQuery::new();
     if let Some(ids) = q.ids {
-        let ids: Vec<String> = ids.split(",")
-            .map(|it| it.to_string())
-            .filter(|it| it.contains("/"))
-            .collect();
-        if ids.len() != 0 {
-            query.ids(ids.iter().map(|xy| {
-                let mut split = xy.split("/");
-                Vector2D::new(split.next().unwrap().parse().unwrap(), split.next().unwrap().parse().unwrap())
-            }).collect());
+        match parse_ids(&ids) {
+            Ok(ids) if ids.is_empty() => {}
+            Ok(ids) => {
+                query.ids(ids);
+            }
+            Err(error) => return Ok(Box::new(with_status(error, StatusCode::BAD_REQUEST)))
         }
     }
     if let Some(offset) = q.offset {
         query.offset(offset);
     }
+    if let Some(after) = q.after {
+        match id2vec(&after) {
+            Some(id) => query.after(id),
+            None => return Ok(Box::new(with_status(format!("Invalid record id {}", after), StatusCode::BAD_REQUEST)))
+        };
+    }
     if let Some(limit) = q.limit {
         query.limit(limit);
     }
     if let Some(column) = q.column {
         query.column(column);
     }
-    match db.get_records(query).await {
-        DBResult::Ok(records) => Ok(Box::new(get_records_json(records, q.embed_refs.unwrap_or(false)))),
-        DBResult::StillLoading(progress) => {
-            Ok(
-                Box::new(with_status(format!("Still loading model ({}%)", (progress*100.0) as u32), StatusCode::PARTIAL_CONTENT))
-            )
-
-        },
-        DBResult::Err(error) => {
-            error!("ERROR {}", error);
-            Ok(Box::new(with_status(error, StatusCode::INTERNAL_SERVER_ERROR)))
+    if let Some(filters) = q.filter {
+        for filter in filters.split(",").filter(|it| !it.is_empty()) {
+            match filter.parse::<Filter>() {
+                Ok(filter) => query.filter(filter),
+                Err(error) => return Ok(Box::new(with_status(error, StatusCode::BAD_REQUEST)))
+            };
         }
     }
+    if let Some(expand) = q.expand {
+        query.expand(expand);
+    }
+    if let Some(order_by) = q.order_by {
+        match order_by.parse::<FieldRef>() {
+            Ok(field) => query.order_by(field, q.desc.unwrap_or(false)),
+            Err(error) => return Ok(Box::new(with_status(error, StatusCode::BAD_REQUEST)))
+        };
+    }
+    let (embed_refs, limit) = (q.embed_refs.unwrap_or(false), q.limit);
+    Ok(reply_for(db.get_records(query).await, |page| {
+        let count = page.records.len();
+        let last_id = page.records.last().map(|r| vec2id(r.id));
+        // a single record can be checked by If-Match like a resource
+        let single_version = match page.records.as_slice() {
+            [record] => Some(record.version),
+            _ => None
+        };
+        let mut response = get_records_json(page.records, embed_refs).into_response();
+        let headers = response.headers_mut();
+        headers.insert("X-Total-Count", page.total.into());
+        if let Some(version) = single_version {
+            headers.insert(ETAG, HeaderValue::from_str(&etag(version)).unwrap());
+        }
+        if page.offset + count < page.total {
+            headers.insert("X-Next-Offset", (page.offset + count).into());
+            if let Some(last_id) = last_id {
+                headers.insert("X-Next-Cursor", HeaderValue::from_str(&last_id).unwrap());
+            }
+        }
+        if page.offset > 0 {
+            let limit = limit.map_or(page.offset, |limit| limit as usize);
+            headers.insert("X-Prev-Offset", page.offset.saturating_sub(limit).into());
+        }
+        Box::new(response)
+    }))
+}
+
+
+
+// Records which fields reference the record, with indexes of such fields
+pub async fn get_referrers_handler(dbname: String, x: u32, y: u32, dbs: DBMAP) -> Result<Box<dyn Reply>, Rejection> {
+    if !dbs.lock().await.contains_key(dbname.as_str()) {
+        return Ok(Box::new(with_status("Unknown db", StatusCode::NOT_FOUND)));
+    }
+    let db = &dbs.lock().await[dbname.as_str()];
+    Ok(reply_for(db.get_referrers(x, y).await, |referrers| {
+        let jsons: Vec<Value> = referrers.iter().map(|referrer| {
+            let mut rec_json = record_json(&referrer.record, false);
+            rec_json["referring_fields"] = json!(referrer.fields);
+            rec_json
+        }).collect();
+        Box::new(warp::reply::json(&jsons))
+    }))
 }
 
+pub async fn get_events_handler(dbname: String, dbs: DBMAP) -> Result<Box<dyn Reply>, Rejection> {
+    if !dbs.lock().await.contains_key(dbname.as_str()) {
+        return Ok(Box::new(with_status("Unknown db", StatusCode::NOT_FOUND)));
+    }
+    let events = dbs.lock().await[dbname.as_str()].subscribe();
+    let stream = BroadcastStream::new(events).map(|event| Ok::<Event, Infallible>(sse_event(event)));
+    Ok(Box::new(warp::sse::reply(warp::sse::keep_alive().stream(stream))))
+}
 
+// Data is the id of the record, "*" (all records) for reload. Events missed by a slow client
+// are reported as reload, so it reads all records again.
+fn sse_event(event: Result<DBEvent, BroadcastStreamRecvError>) -> Event {
+    match event {
+        Ok(DBEvent::RecordChanged { id }) => Event::default().event("record-changed").data(vec2id(id)),
+        Ok(DBEvent::RecordCreated { id }) => Event::default().event("record-created").data(vec2id(id)),
+        Ok(DBEvent::RecordDeleted { id }) => Event::default().event("record-deleted").data(vec2id(id)),
+        Ok(DBEvent::ModelReloaded) | Err(BroadcastStreamRecvError::Lagged(_)) => Event::default().event("model-reloaded").data("*"),
+    }
+}
 
-pub async fn put_field_handler(dbname: String, x: u32, y: u32, fi: u32, dbs: DBMAP, json: Value) -> Result<impl Reply, Rejection> {
+// With If-Match the field is written only if the record wasn't changed since the client got it,
+// the new version of the record is returned as ETag
+#[allow(clippy::too_many_arguments)]
+pub async fn put_field_handler(dbname: String, x: u32, y: u32, fi: u32, dbs: DBMAP, json: Value, client: String, if_match: Option<String>) -> Result<Box<dyn Reply>, Rejection> {
     if !dbs.lock().await.contains_key(dbname.as_str()) {
-        return Ok(with_status("Unknown db".to_string(), StatusCode::NOT_FOUND));
+        return Ok(Box::new(with_status("Unknown db".to_string(), StatusCode::NOT_FOUND)));
     }
+    let expected_versions = match parse_if_match(if_match) {
+        Ok(version) => version,
+        Err(error) => return Ok(Box::new(with_status(error, StatusCode::BAD_REQUEST)))
+    };
     if let Value::Object(ref _obj) = json {
         let db = &dbs.lock().await[dbname.as_str()];
-        let value = from_json(&json).unwrap(); //todo: err
-        match db.set_field(x, y, fi, value).await {
-            DBResult::Ok(_) => Ok(with_status("Ok".to_string(), StatusCode::OK)),
-            DBResult::StillLoading(progress) => {
-                Ok(
-                    with_status(format!("Still loading model ({}%)", (progress*100.0) as u32), StatusCode::PARTIAL_CONTENT)
-                )
-
-            },
-            DBResult::Err(error) => {
-                error!("ERROR {}", error);
-                Ok(with_status(error, StatusCode::INTERNAL_SERVER_ERROR))
+        let value = match from_json(&json) {
+            Ok(value) => value,
+            Err((expected, given)) => return Ok(Box::new(invalid_value_reply(&InvalidValue { field: FieldRef::Index(fi), expected, given })))
+        };
+        Ok(reply_for(db.set_field(x, y, fi, value, client, expected_versions).await, |version| Box::new(warp::reply::with_header(with_status("Ok".to_string(), StatusCode::OK), ETAG, etag(version)))))
+    } else {
+        Ok(Box::new(with_status("Invalid json".to_string(), StatusCode::BAD_REQUEST)))
+    }
+
+}
+
+// Body is {"reference": "x/y"} to point the reference field to the record or {"reference": null} to remove the reference
+pub async fn put_reference_handler(dbname: String, x: u32, y: u32, fi: u32, dbs: DBMAP, json: Value) -> Result<Box<dyn Reply>, Rejection> {
+    if !dbs.lock().await.contains_key(dbname.as_str()) {
+        return Ok(Box::new(with_status("Unknown db".to_string(), StatusCode::NOT_FOUND)));
+    }
+    let target = match json.get("reference") {
+        Some(Value::Null) => None,
+        Some(Value::String(id)) => match id2vec(id) {
+            Some(target) => Some(target),
+            None => return Ok(Box::new(with_status(format!("Invalid record id {}", id), StatusCode::BAD_REQUEST)))
+        },
+        _ => return Ok(Box::new(with_status("Invalid json".to_string(), StatusCode::BAD_REQUEST)))
+    };
+    let db = &dbs.lock().await[dbname.as_str()];
+    Ok(reply_for(db.set_reference(x, y, fi, target).await, |_| Box::new(with_status("Ok".to_string(), StatusCode::OK))))
+}
+
+// Body is an object with field indexes or names as keys and {"type": .., "value": ..} as values
+pub async fn patch_record_handler(dbname: String, x: u32, y: u32, dbs: DBMAP, json: Value, client: String) -> Result<Box<dyn Reply>, Rejection> {
+    if !dbs.lock().await.contains_key(dbname.as_str()) {
+        return Ok(Box::new(with_status("Unknown db".to_string(), StatusCode::NOT_FOUND)));
+    }
+    if let Value::Object(ref obj) = json {
+        let mut values = vec![];
+        for (key, field_json) in obj {
+            let field: FieldRef = match key.parse() {
+                Ok(field) => field,
+                Err(error) => return Ok(Box::new(with_status(error, StatusCode::BAD_REQUEST)))
+            };
+            match from_json(field_json) {
+                Ok(value) => values.push((field, value)),
+                Err((expected, given)) => return Ok(Box::new(invalid_value_reply(&InvalidValue { field, expected, given })))
             }
         }
+        let db = &dbs.lock().await[dbname.as_str()];
+        Ok(reply_for(db.set_fields(x, y, values, client).await, |_| Box::new(with_status("Ok".to_string(), StatusCode::OK))))
     } else {
-        Ok(with_status("Invalid json".to_string(), StatusCode::INTERNAL_SERVER_ERROR))
+        Ok(Box::new(with_status("Invalid json".to_string(), StatusCode::BAD_REQUEST)))
     }
+}
 
+// Reply with the value by `ok`, other results are answered the same way by all handlers
+fn reply_for<T>(result: DBResult<T>, ok: impl FnOnce(T) -> Box<dyn Reply>) -> Box<dyn Reply> {
+    match result {
+        DBResult::Ok(value) => ok(value),
+        DBResult::StillLoading(progress) => {
+            Box::new(with_status(format!("Still loading model ({}%)", (progress*100.0) as u32), StatusCode::PARTIAL_CONTENT))
+        }
+        DBResult::Invalid(invalid) => Box::new(invalid_value_reply(&invalid)),
+        DBResult::NotFound(message) => Box::new(with_status(message, StatusCode::NOT_FOUND)),
+        DBResult::BadRequest(message) => Box::new(with_status(message, StatusCode::BAD_REQUEST)),
+        DBResult::Conflict(version) => Box::new(conflict_reply(version)),
+        DBResult::Err(error) => {
+            error!("ERROR {}", error);
+            Box::new(with_status(error, StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
 }
 
-fn get_records_json(records: Vec<DataRecord>, embed_refs: bool) -> Json {
-    let mut jsons = vec![];
-    for rec in records.iter() {
-        let mut field_jsons = vec![];
-        for field in &rec.fields {
-            field_jsons.push(to_json(&field, embed_refs))
+// 412 with the current version of the record
+fn conflict_reply(version: u64) -> impl Reply {
+    warp::reply::with_header(with_status("Record was changed".to_string(), StatusCode::PRECONDITION_FAILED), ETAG, etag(version))
+}
+
+// Strong ETag of the record version
+fn etag(version: u64) -> String {
+    format!("\"{:016x}\"", version)
+}
+
+// Versions of the strong ETags listed in If-Match (RFC 7232), None for "*" or no header, which match any version.
+// Weak and foreign tags match no version. Err for a value which is not a list of ETags
+fn parse_if_match(if_match: Option<String>) -> Result<Option<Vec<u64>>, String> {
+    let value = match if_match.as_deref().map(|value| value.trim()) {
+        None | Some("*") => return Ok(None),
+        Some(value) => value,
+    };
+    let invalid = || format!("Invalid If-Match {}", value);
+    let mut versions = vec![];
+    let mut rest = value;
+    loop {
+        let (weak, tag) = match rest.strip_prefix("W/") {
+            Some(tag) => (true, tag),
+            None => (false, rest),
+        };
+        let tag = tag.strip_prefix('"').ok_or_else(invalid)?;
+        let end = tag.find('"').ok_or_else(invalid)?;
+        if !weak {
+            if let Ok(version) = u64::from_str_radix(&tag[..end], 16) {
+                versions.push(version);
+            }
+        }
+        rest = tag[end + 1..].trim_start();
+        if rest.is_empty() {
+            return Ok(Some(versions));
         }
-        let rec_json = json![{
-            "id": vec2id(rec.id),
-            "column": rec.column,
-            "fields": field_jsons
-        }];
-        jsons.push(rec_json);
+        rest = rest.strip_prefix(',').ok_or_else(invalid)?.trim_start();
     }
+}
+
+fn invalid_value_reply(invalid: &InvalidValue) -> WithStatus<Json> {
+    let field = match &invalid.field {
+        FieldRef::Index(idx) => json!(idx),
+        FieldRef::Name(name) => json!(name),
+    };
+    with_status(warp::reply::json(&json!({
+        "field": field,
+        "expected": invalid.expected,
+        "given": invalid.given,
+    })), StatusCode::BAD_REQUEST)
+}
+
+fn get_records_json(records: Vec<DataRecord>, embed_refs: bool) -> Json {
+    let jsons: Vec<Value> = records.iter().map(|rec| record_json(rec, embed_refs)).collect();
     warp::reply::json(&jsons)
 }
 
+fn record_json(rec: &DataRecord, embed_refs: bool) -> Value {
+    let mut field_jsons = vec![];
+    for field in &rec.fields {
+        let mut field_json = to_json(field, embed_refs);
+        if let Some(record) = &field.record {
+            field_json["record"] = record_json(record, embed_refs);
+        }
+        field_jsons.push(field_json)
+    }
+    json![{
+        "id": vec2id(rec.id),
+        "column": rec.column,
+        "column_name": rec.column_name,
+        "etag": etag(rec.version),
+        "fields": field_jsons
+    }]
+}
+
 
 pub(crate) fn vec2id(vector: Vector2D) -> String {
     format!("{}/{}", vector.x, vector.y)
 }
+
+pub(crate) fn id2vec(id: &str) -> Option<Vector2D> {
+    let mut split = id.split("/");
+    let x = split.next()?.parse().ok()?;
+    let y = split.next()?.parse().ok()?;
+    match split.next() {
+        Some(_) => None,
+        None => Some(Vector2D::new(x, y))
+    }
+}
+
+// Comma separated "x/y" ids, empty items are skipped
+fn parse_ids(ids: &str) -> Result<Vec<Vector2D>, String> {
+    ids.split(",")
+        .filter(|it| !it.is_empty())
+        .map(|it| id2vec(it).ok_or(format!("Invalid record id {}", it)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use badbee_backend::db::DBHandle;
+
+    #[test]
+    fn ids_are_parsed() {
+        assert_eq!(parse_ids("1/2,30/40,").unwrap(), vec![Vector2D::new(1, 2), Vector2D::new(30, 40)]);
+        assert!(parse_ids("").unwrap().is_empty());
+        assert_eq!(parse_ids("1/2,abc/def").unwrap_err(), "Invalid record id abc/def");
+        assert_eq!(parse_ids("1").unwrap_err(), "Invalid record id 1");
+        assert_eq!(parse_ids("1/2/3").unwrap_err(), "Invalid record id 1/2/3");
+    }
+
+    #[test]
+    fn if_match_is_parsed() {
+        assert_eq!(parse_if_match(None).unwrap(), None);
+        assert_eq!(parse_if_match(Some(" * ".to_string())).unwrap(), None);
+        assert_eq!(parse_if_match(Some(etag(42))).unwrap(), Some(vec![42]));
+        let list = format!("{}, W/{},\"other\" ,{}", etag(1), etag(2), etag(3));
+        assert_eq!(parse_if_match(Some(list)).unwrap(), Some(vec![1, 3]));
+        // only weak or foreign tags match nothing
+        assert_eq!(parse_if_match(Some(format!("W/{}", etag(2)))).unwrap(), Some(vec![]));
+        assert_eq!(parse_if_match(Some("\"a,b\"".to_string())).unwrap(), Some(vec![]));
+        for value in ["", "42", "\"42", "\"1\" \"2\"", "\"1\","] {
+            assert_eq!(parse_if_match(Some(value.to_string())).unwrap_err(), format!("Invalid If-Match {}", value));
+        }
+    }
+
+    // Field is written only when the record version is in the If-Match list
+    #[tokio::test]
+    async fn field_is_written_if_match() {
+        // fonts are next to the workspace manifest
+        std::env::set_current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/..")).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.png");
+        image::RgbImage::from_pixel(100, 100, image::Rgb([255, 255, 255])).save(&path).unwrap();
+        let db = DBHandle::run_in_background(path.to_str().unwrap());
+        let id = loop {
+            let created = db.create_record(RGB::new(0xED, 0x1C, 0x24), vec![FieldType::by_name("int").unwrap()], vec![Vector2D::new(10, 10)], vec![None]).await;
+            match created {
+                DBResult::StillLoading(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+                created => break created.unwrap().id,
+            }
+        };
+        let dbs: DBMAP = Arc::new(Mutex::new(HashMap::new()));
+        dbs.lock().await.insert("db".to_string(), db.clone());
+        let put = |value: i64, if_match: Option<String>| {
+            let dbs = dbs.clone();
+            async move {
+                let reply = put_field_handler("db".to_string(), id.x, id.y, 0, dbs, json!({"type": "int", "value": value}), "test".to_string(), if_match).await.unwrap();
+                let response = reply.into_response();
+                let etag = response.headers().get(ETAG).map(|etag| etag.to_str().unwrap().to_string());
+                (response.status(), etag)
+            }
+        };
+
+        let (status, current) = put(1, Some("*".to_string())).await;
+        assert_eq!(status, StatusCode::OK);
+        let current = current.unwrap();
+        let (status, changed) = put(2, Some(format!("W/{}, {}", current, etag(0)))).await;
+        assert_eq!((status, changed.as_ref()), (StatusCode::PRECONDITION_FAILED, Some(&current)));
+        let (status, next) = put(2, Some(format!("{}, {}", etag(0), current))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(put(3, Some(current)).await.0, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(put(3, Some(next.unwrap())).await.0, StatusCode::OK);
+        assert_eq!(put(4, Some("42".to_string())).await.0, StatusCode::BAD_REQUEST);
+        db.shutdown().await;
+    }
+}
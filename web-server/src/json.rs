@@ -1,7 +1,9 @@
 use serde_json::{Value, json};
-use badbee_backend::model::model::{DataValue};
+use badbee_backend::model::model::{DataValue, FieldType};
+use badbee_backend::model::colors::RGB;
 use crate::handlers::vec2id;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use badbee_backend::db::DataFieldValue;
 
 pub(crate) fn to_json(val: &DataFieldValue, embed_refs: bool) -> Value {
@@ -23,6 +25,9 @@ pub(crate) fn to_json(val: &DataFieldValue, embed_refs: bool) -> Value {
         }),
         DataValue::Custom { subtype, value } => json!({"type": subtype, "value": value})
     };
+    if let Some(name) = &val.name {
+        out["name"] = json!(name);
+    }
     if let Some(id) = val.reference {
         out["reference"] = json!(vec2id(id));
         if !embed_refs {
@@ -32,32 +37,93 @@ pub(crate) fn to_json(val: &DataFieldValue, embed_refs: bool) -> Value {
     json!(out)
 }
 
-pub(crate) fn from_json(val: &Value) -> Option<DataValue> {
-    //todo: check "type" as well
-    let _vtype = val.get("type").and_then(|v| v.as_str());
-    let val = val.get("value")?;
+// Parses {"type": .., "value": ..}, value is parsed as declared type or by its json type if "type" is missing.
+// Error tells what was expected and what was given instead.
+pub(crate) fn from_json(val: &Value) -> Result<DataValue, (String, String)> {
+    let vtype = val.get("type").and_then(|v| v.as_str());
+    let value = val.get("value").ok_or(("value".to_string(), "nothing".to_string()))?;
+    match vtype {
+        Some(vtype) => {
+            if !TYPES.contains(&vtype) {
+                let given = match FieldType::by_name(vtype) {
+                    // there is no value to write, the field is pointed to a record with PUT .../reference
+                    Some(_) => format!("type {} which has no value", vtype),
+                    None => format!("type {}", vtype)
+                };
+                return Err((format!("one of types: {}", TYPES.join(", ")), given));
+            }
+            parse_value(vtype, value).ok_or((vtype.to_string(), value.to_string()))
+        }
+        None => guess_value(value).ok_or((format!("value of one of types: {}", TYPES.join(", ")), value.to_string()))
+    }
+}
+
+// field types with values, all field types but "reference"
+const TYPES: [&str; 7] = ["boolean", "int", "float", "string", "color", "image", "pie"];
+
+fn parse_value(vtype: &str, val: &Value) -> Option<DataValue> {
+    match vtype {
+        "boolean" => val.as_bool().map(|value| DataValue::Boolean { value }),
+        "int" => val.as_i64()
+            .and_then(|value| i32::try_from(value).ok())
+            .map(|value| DataValue::Int { value }),
+        "float" => val.as_f64().map(|value| DataValue::Float { value: value as f32 }),
+        "string" => val.as_str().map(|value| DataValue::String { value: value.to_string() }),
+        "color" => val.as_str()
+            .and_then(RGB::from_hex_color)
+            .map(|value| DataValue::Color { value }),
+        "image" => {
+            let obj = val.as_object()?;
+            Some(DataValue::Image {
+                width: obj.get("width")?.as_u64()? as u32,
+                height: obj.get("height")?.as_u64()? as u32,
+                data_url: obj.get("data_url")?.as_str()?.to_string(),
+            })
+        }
+        "pie" => {
+            let mut value = HashMap::new();
+            for (k, v) in val.as_object()? {
+                value.insert(RGB::from_hex_color(k)?, v.as_f64()? as f32);
+            }
+            Some(DataValue::Histogram { value })
+        }
+        _ => None
+    }
+}
+
+fn guess_value(val: &Value) -> Option<DataValue> {
     match val {
         Value::Null => None,
-        Value::Bool(value) => Some(DataValue::Boolean { value: *value }),
-        Value::Number(value) if value.is_f64() => Some(DataValue::Float { value: value.as_f64().unwrap() as f32 }),
-        Value::Number(value) => Some(DataValue::Int { value: value.as_i64().unwrap() as i32 }),
-        Value::String(value) if value.starts_with("#") => Some(DataValue::Color { value: value.into() }),
-        Value::String(value) => Some(DataValue::String { value: value.clone() }),
+        Value::Bool(_) => parse_value("boolean", val),
+        Value::Number(value) if value.is_f64() => parse_value("float", val),
+        Value::Number(_) => parse_value("int", val),
+        Value::String(value) if value.starts_with("#") => parse_value("color", val),
+        Value::String(_) => parse_value("string", val),
         Value::Array(_) => None,
-        Value::Object(obj) if obj.contains_key("data_url") =>
+        Value::Object(obj) if obj.contains_key("data_url") => parse_value("image", val),
         //todo: support other objects
-            Some(DataValue::Image {
-                width: obj["width"].as_i64().unwrap() as u32,
-                height: obj["height"].as_i64().unwrap() as u32,
-                data_url: obj["data_url"].as_str().unwrap().to_string(),
-            }),
-        Value::Object(obj) =>
-            Some(DataValue::Histogram {
-                value: obj.iter()
-                    .map(|(k, v)| (k.into(), v.as_f64().unwrap() as f32))
-                    .collect()
-            })
+        Value::Object(_) => parse_value("pie", val)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_types_are_field_types_with_values() {
+        let mut expected: Vec<&str> = FieldType::names().filter(|name| *name != "reference").collect();
+        expected.sort_unstable();
+        let mut types = TYPES.to_vec();
+        types.sort_unstable();
+        assert_eq!(types, expected);
+    }
 
+    #[test]
+    fn reference_is_not_a_value_type() {
+        let (expected, given) = from_json(&json!({"type": "reference", "value": "1/2"})).unwrap_err();
+        assert_eq!(expected, format!("one of types: {}", TYPES.join(", ")));
+        assert_eq!(given, "type reference which has no value");
+        assert_eq!(from_json(&json!({"type": "number", "value": 1})).unwrap_err().1, "type number");
+    }
+}
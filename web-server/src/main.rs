@@ -5,8 +5,10 @@ use warp::{Filter};
 use std::collections::HashMap;
 use serde_derive::Deserialize;
 use std::time::Duration;
+use std::net::SocketAddr;
 use badbee_backend::db::DBHandle;
-use crate::handlers::{get_records_handler, put_field_handler, get_model_handler, clone_record_handler, get_dbs_handler};
+use badbee_backend::io::bitmap_font::DEFAULT_FONT;
+use crate::handlers::{get_records_handler, put_field_handler, get_model_handler, clone_record_handler, get_dbs_handler, create_record_handler, delete_record_handler, patch_record_handler, get_schema_handler, get_referrers_handler, put_reference_handler, get_events_handler, create_snapshot_handler, get_snapshots_handler, restore_snapshot_handler, get_history_handler, revert_field_handler};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tokio::signal;
@@ -21,6 +23,31 @@ pub struct RecordsQuery {
     ids: Option<String>,
     //comma-separated
     embed_refs: Option<bool>,
+    // levels of referenced records to include into reference fields
+    expand: Option<u32>,
+    // id of the last record of previous page, more stable than offset when records are added
+    after: Option<String>,
+    //comma-separated field:op:value, e.g. 2:eq:true,4:gt:0.5
+    filter: Option<String>,
+    // field index or name
+    order_by: Option<String>,
+    desc: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct NewRecord {
+    column: String,
+    fields: Vec<NewField>,
+}
+
+#[derive(Deserialize)]
+pub struct NewField {
+    #[serde(rename = "type")]
+    ftype: String,
+    width: u32,
+    height: u32,
+    // only for string fields, default font of the db is used if not set
+    font: Option<String>,
 }
 
 #[tokio::main]
@@ -31,14 +58,25 @@ async fn main() {
 
     let dbs: DBMAP = Arc::new(Mutex::new(HashMap::new()));
 
+    // default font of string fields, the same for all databases of the process
+    let font = std::env::var("DB_FONT").unwrap_or(DEFAULT_FONT.to_string());
+    // older snapshots are removed when a new one is created, the new one is always kept
+    let snapshots_keep = std::env::var("SNAPSHOTS_KEEP").unwrap_or("10".to_string());
+    let snapshots_keep = match snapshots_keep.parse::<usize>() {
+        Ok(keep) if keep >= 1 => keep,
+        _ => {
+            log::error!("SNAPSHOTS_KEEP should be a number of snapshots to keep, 1 or more, not {:?}", snapshots_keep);
+            std::process::exit(1);
+        }
+    };
     match std::env::var("DB_FILE") {
         Ok(value) => {
             log::info!("Load db specified in DB_NAME env var ({})", value.clone());
-            dbs.lock().await.insert(value.clone(), DBHandle::run_in_background(format!("db/{}", value).as_str()));
+            dbs.lock().await.insert(value.clone(), DBHandle::run_in_background_with_font(format!("db/{}", value).as_str(), &font));
         }
         Err(_) => {
             log::info!("Load default db");
-            dbs.lock().await.insert("db".to_string(), DBHandle::run_in_background("db/db.png"));
+            dbs.lock().await.insert("db".to_string(), DBHandle::run_in_background_with_font("db/db.png", &font));
         }
     }
 
@@ -61,6 +99,13 @@ async fn main() {
 
     let with_dbs_filter = with_dbs(dbs);
 
+    // the writer of changes in the history: X-Client header if the client names itself, its ip address otherwise
+    let with_client = warp::header::optional::<String>("X-Client")
+        .and(warp::addr::remote())
+        .map(|client: Option<String>, addr: Option<SocketAddr>| {
+            client.or(addr.map(|addr| addr.ip().to_string())).unwrap_or("unknown".to_string())
+        });
+
     let get_dbs = warp::path!("dbs.json")
         .and(with_dbs_filter.clone())
         .and_then(get_dbs_handler);
@@ -74,29 +119,106 @@ async fn main() {
         .and(with_dbs_filter.clone())
         .and_then(get_model_handler);
 
+    let get_schema = warp::path!(String / "schema.json")
+        .and(with_dbs_filter.clone())
+        .and_then(get_schema_handler);
+
+    let get_referrers = warp::path!(String / "records" / u32 / u32 / "referrers.json")
+        .and(with_dbs_filter.clone())
+        .and_then(get_referrers_handler);
+
+    let get_events = warp::path!(String / "events")
+        .and(with_dbs_filter.clone())
+        .and_then(get_events_handler);
+
     let put_field = warp::put()
         .and(warp::path!(String / "records" / u32 / u32 / u32))
         .and(with_dbs_filter.clone())
         .and(warp::body::json())
+        .and(with_client)
+        .and(warp::header::optional::<String>("If-Match"))
         .and_then(put_field_handler)
         ;
 
+    let put_reference = warp::put()
+        .and(warp::path!(String / "records" / u32 / u32 / u32 / "reference"))
+        .and(with_dbs_filter.clone())
+        .and(warp::body::json())
+        .and_then(put_reference_handler);
+
+    let patch_record = warp::patch()
+        .and(warp::path!(String / "records" / u32 / u32))
+        .and(with_dbs_filter.clone())
+        .and(warp::body::json())
+        .and(with_client)
+        .and_then(patch_record_handler);
+
+    let get_history = warp::path!(String / "records" / u32 / u32 / "history.json")
+        .and(with_dbs_filter.clone())
+        .and_then(get_history_handler);
+
+    let revert_field = warp::post()
+        .and(warp::path!(String / "records" / u32 / u32 / "history" / u64 / "revert"))
+        .and(with_dbs_filter.clone())
+        .and(with_client)
+        .and_then(revert_field_handler);
+
     let clone_record = warp::post()
         .and(warp::path!(String / "records" / u32 / u32 / "clone"))
         .and(with_dbs_filter.clone())
+        .and(warp::header::optional::<String>("If-Match"))
         .and_then(clone_record_handler);
 
+    let create_record = warp::post()
+        .and(warp::path!(String / "records"))
+        .and(with_dbs_filter.clone())
+        .and(warp::body::json())
+        .and_then(create_record_handler);
+
+    let delete_record = warp::delete()
+        .and(warp::path!(String / "records" / u32 / u32))
+        .and(with_dbs_filter.clone())
+        .and_then(delete_record_handler);
+
+    let create_snapshot = warp::post()
+        .and(warp::path!(String / "snapshots"))
+        .and(with_dbs_filter.clone())
+        .and(warp::any().map(move || snapshots_keep))
+        .and_then(create_snapshot_handler);
+
+    let get_snapshots = warp::path!(String / "snapshots.json")
+        .and(with_dbs_filter.clone())
+        .and_then(get_snapshots_handler);
+
+    let restore_snapshot = warp::post()
+        .and(warp::path!(String / "snapshots" / u64 / "restore"))
+        .and(with_dbs_filter.clone())
+        .and_then(restore_snapshot_handler);
+
     let cors = warp::cors()
         .allow_any_origin()
-        .allow_headers(vec!["User-Agent", "Sec-Fetch-Mode", "Referer", "Origin", "Access-Control-Request-Method", "Access-Control-Request-Headers", "Content-Type"])
+        .allow_headers(vec!["User-Agent", "Sec-Fetch-Mode", "Referer", "Origin", "Access-Control-Request-Method", "Access-Control-Request-Headers", "Content-Type", "X-Client", "If-Match"])
         .allow_methods(vec!["POST", "GET", "PUT", "PATCH", "DELETE"])
+        .expose_headers(vec!["X-Total-Count", "X-Next-Offset", "X-Prev-Offset", "X-Next-Cursor", "ETag"])
         .build();
 
     let routes = get_dbs
         .or(get_records)
         .or(put_field)
+        .or(put_reference)
+        .or(patch_record)
         .or(get_model)
-        .or(clone_record);
+        .or(get_schema)
+        .or(get_referrers)
+        .or(get_events)
+        .or(clone_record)
+        .or(create_record)
+        .or(delete_record)
+        .or(create_snapshot)
+        .or(get_snapshots)
+        .or(restore_snapshot)
+        .or(get_history)
+        .or(revert_field);
     let static_files = warp::get().and(warp::fs::dir("static"));
 
     let (_, server) = warp::serve(routes.or(static_files).with(cors))
@@ -112,8 +234,7 @@ async fn main() {
     periodical_sync.abort();
     log::info!("stopped periodical");
     for db_handle in shutdown_dbs.lock().await.values() {
-        db_handle.sync().await;
-        db_handle.shutdown();
+        db_handle.shutdown().await;
     }
     log::info!("stopped dbs");
 